@@ -0,0 +1,80 @@
+use std::{env, fs, sync::Arc};
+
+use {
+    criterion::{criterion_group, criterion_main, Criterion},
+    tokio::{
+        runtime::Runtime,
+        sync::{mpsc, Mutex},
+    },
+};
+
+use scriptkeys::{
+    config::Config,
+    device::{Action, Event},
+    script::Script,
+};
+
+const CONFIG: &str = r#"
+device = 'Dummy'
+
+[[mappings]]
+key = 0
+script = 'Bench.lua'
+"#;
+
+const SCRIPT: &str = r#"
+Bench = Bench or {}
+
+function Bench.Press()
+end
+
+function Bench.Release()
+end
+"#;
+
+/// Loads a script into a fresh state under a scratch directory, so the
+/// benchmark doesn't read or write the user's own scripts and state.
+fn setup(runtime: &Runtime) -> Arc<Mutex<Script>> {
+    let dir = env::temp_dir().join("scriptkeys-bench");
+    fs::create_dir_all(dir.join(".scripts")).unwrap();
+    fs::write(dir.join("config.toml"), CONFIG).unwrap();
+    fs::write(dir.join(".scripts").join("Bench.lua"), SCRIPT).unwrap();
+
+    env::set_current_dir(&dir).unwrap();
+    env::set_var("HOME", &dir);
+
+    let config = Config::new(&dir.join("config.toml")).unwrap();
+    let (enigo_tx, _enigo_rx) = mpsc::channel(32);
+    let (device_tx, _device_rx) = mpsc::channel(32);
+
+    runtime
+        .block_on(Script::new(config, enigo_tx, device_tx))
+        .unwrap()
+}
+
+fn dispatch(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let script = setup(&runtime);
+
+    let press = Event {
+        key: 0,
+        action: Action::Press,
+    };
+    let release = Event {
+        key: 0,
+        action: Action::Release,
+    };
+
+    c.bench_function("dispatch press and release", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut script = script.lock().await;
+                script.dispatch(&press);
+                script.dispatch(&release);
+            })
+        })
+    });
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
@@ -0,0 +1,148 @@
+use std::path::Path;
+
+use {
+    anyhow::{Error, Result},
+    mlua::{Lua, LuaOptions, LuaSerdeExt, StdLib, Table, Value},
+};
+
+use crate::{
+    config::{Config, Limits},
+    errors::{InvalidMappingAction, LuaConfigNotTable},
+    helper::{define_host, define_setmetatable},
+    script::Limiter,
+};
+
+/// The `os` functions scripts get, so `init.lua` sees the same ones whether
+/// it's building the config or registering handlers.
+const SAFE_OS: [&str; 4] = ["clock", "date", "difftime", "time"];
+
+/// Builds the config from an `init.lua`, which returns a table shaped like
+/// the TOML config. The file runs in a bare state with the standard library
+/// scripts get and the host helpers. `on` does nothing here; the handlers it
+/// registers are picked up when the file is loaded again as a script. The
+/// config's own limits aren't known until it has run, so the default ones
+/// apply.
+pub fn evaluate(path: &Path, text: &str) -> Result<Config> {
+    evaluate_within(path, text, Limits::default())
+}
+
+fn evaluate_within(path: &Path, text: &str, limits: Limits) -> Result<Config> {
+    let lua = Lua::new_with(
+        StdLib::COROUTINE
+            | StdLib::MATH
+            | StdLib::OS
+            | StdLib::STRING
+            | StdLib::TABLE
+            | StdLib::UTF8,
+        LuaOptions::new(),
+    )?;
+
+    let globals = lua.globals();
+    let os: Table = globals.get("os")?;
+    let safe_os = lua.create_table()?;
+    for name in SAFE_OS {
+        safe_os.set(name, os.get::<_, Value>(name)?)?;
+    }
+    globals.set("os", safe_os)?;
+    define_host(&lua, &globals)?;
+    define_setmetatable(&lua, &globals)?;
+    globals.set(
+        "on",
+        lua.create_function(|_lua, _: mlua::MultiValue| Ok(()))?,
+    )?;
+
+    let limiter = Limiter::default();
+    limiter.install(&lua)?;
+    limiter.define(&lua, &globals)?;
+    limiter.set_limits(&lua, limits)?;
+
+    limiter.start();
+    let value = lua
+        .load(text)
+        .set_name(format!("@{}", path.display()))?
+        .eval::<Value>();
+    limiter.stop();
+    let value = value?;
+
+    let table = match &value {
+        Value::Table(table) => table,
+        _ => return Err(Error::new(LuaConfigNotTable)),
+    };
+    check_snippets(table)?;
+
+    Ok(lua.from_value(value)?)
+}
+
+/// Inline snippets only make sense in TOML, where the handler can't be
+/// written as Lua directly. Rejects them with a clearer error than the
+/// deserializer would give.
+fn check_snippets(config: &Table) -> Result<()> {
+    let mut lists = vec![config.get::<_, Value>("mappings")?];
+    if let Value::Table(layers) = config.get("layers")? {
+        for pair in layers.pairs::<Value, Value>() {
+            if let (_, Value::Table(layer)) = pair? {
+                lists.push(layer.get("mappings")?);
+            }
+        }
+    }
+
+    for list in lists {
+        let Value::Table(list) = list else { continue };
+        for mapping in list.sequence_values::<Value>() {
+            let Value::Table(mapping) = mapping? else {
+                continue;
+            };
+            if mapping.contains_key("press")? || mapping.contains_key("release")? {
+                return Err(Error::new(InvalidMappingAction(String::from(
+                    "press/release snippets are for TOML configs, use on() in init.lua",
+                ))));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let text = r#"
+            local mappings = {}
+            for key = 0, 2 do
+                table.insert(mappings, { key = key, script = "Key" .. key .. ".lua" })
+            end
+            on("connect", function() end)
+            return {
+                device = "Dummy",
+                mappings = mappings,
+                layers = { video = { mappings = { { keys = "3..4", combo = "Space" } } } },
+            }
+        "#;
+        let config = evaluate(Path::new("init.lua"), text).unwrap();
+        assert_eq!(config.mappings.len(), 3);
+        assert_eq!(config.mappings[2].script.as_deref(), Some("Key2.lua"));
+        assert_eq!(
+            config.layers["video"].mappings[0].combo.as_deref(),
+            Some("Space")
+        );
+
+        assert!(evaluate(Path::new("init.lua"), "return 1").is_err());
+        assert!(evaluate(
+            Path::new("init.lua"),
+            r#"return { device = "Dummy", mappings = { { key = 0, press = "x()" } } }"#
+        )
+        .is_err());
+
+        let limits = Limits {
+            time: 50,
+            instructions: 0,
+            memory: 0,
+        };
+        let runaway = "while true do pcall(function() while true do end end) end";
+        let err = evaluate_within(Path::new("init.lua"), runaway, limits).unwrap_err();
+        assert!(err.to_string().contains("time limit exceeded"));
+    }
+}
@@ -1,5 +1,9 @@
+mod lua;
+
 use std::{
+    collections::HashMap,
     fs,
+    ops::Range,
     path::{Path, PathBuf},
     sync::Arc,
 };
@@ -15,13 +19,15 @@ use {
         mpsc::{channel, Receiver},
         Mutex,
     },
+    tokio::time::timeout,
+    toml::Spanned,
 };
 
 use crate::{
-    constants::{CONFIG_FILE_NAMES, CONFIG_FILE_PATHS},
+    constants::{CONFIG_FILE_NAMES, CONFIG_FILE_PATHS, MAX_KEY, WATCH_DEBOUNCE},
     device::Devices,
-    errors::ConfigPathNotFound,
-    helper::parse_path,
+    errors::{ConfigPathNotFound, InvalidMappingAction, InvalidMappingKeys},
+    helper::{is_file_change, parse_path},
 };
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -33,30 +39,340 @@ pub enum ConfigEvent {
 
 #[derive(Deserialize, PartialEq, Debug)]
 pub struct Mapping {
-    pub key: u32,
+    #[serde(default)]
+    pub key: Option<u32>,
+    #[serde(default)]
+    pub keys: Option<Keys>,
+    #[serde(default)]
+    pub script: Option<String>,
+    #[serde(default, rename = "type")]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub combo: Option<String>,
+    #[serde(default)]
+    pub run: Option<Vec<String>>,
+    #[serde(default)]
+    pub layer: Option<String>,
+    #[serde(default)]
+    pub press: Option<Spanned<String>>,
+    #[serde(default)]
+    pub release: Option<Spanned<String>>,
+    #[serde(default)]
+    pub repeat: Option<Repeat>,
+    #[serde(default)]
+    pub mode: Mode,
+    #[serde(default)]
+    pub led: bool,
+    #[serde(default)]
+    pub params: Option<toml::Table>,
+}
+
+/// The keys a mapping applies to when given as `keys`: a list (`[0, 1, 2]`),
+/// an inclusive range (`"0..19"`), or `"*"` for every key that doesn't have a
+/// mapping of its own.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(untagged)]
+pub enum Keys {
+    List(Vec<u32>),
+    Pattern(String),
+}
+
+#[derive(PartialEq, Debug)]
+pub enum MappedKeys {
+    Keys(Vec<u32>),
+    Default,
+}
+
+/// What a mapping does: call a script, or one of the built-in actions which
+/// need no Lua at all.
+#[derive(PartialEq, Debug)]
+pub enum MappingAction {
+    Script(String),
+    Text(String),
+    Combo(String),
+    Run(Vec<String>),
+    Layer(String),
+    Inline {
+        press: Option<Spanned<String>>,
+        release: Option<Spanned<String>>,
+    },
+}
+
+/// A named set of mappings. While a layer is active its mappings take
+/// precedence over the top level ones.
+#[derive(Deserialize, PartialEq, Debug, Default)]
+pub struct Layer {
+    #[serde(default)]
+    pub mappings: Vec<Mapping>,
+}
+
+impl Mapping {
+    /// Resolves `key` or `keys`, exactly one of which must be set.
+    pub fn mapped_keys(&self) -> Result<MappedKeys> {
+        let target = self.script.as_deref().unwrap_or("a built-in action");
+        let invalid = || Error::new(InvalidMappingKeys(String::from(target)));
+
+        match (self.key, &self.keys) {
+            (Some(key), None) if key <= MAX_KEY => Ok(MappedKeys::Keys(vec![key])),
+            (None, Some(Keys::List(keys)))
+                if !keys.is_empty() && keys.iter().all(|&key| key <= MAX_KEY) =>
+            {
+                Ok(MappedKeys::Keys(keys.clone()))
+            }
+            (None, Some(Keys::Pattern(pattern))) => match pattern.trim() {
+                "*" => Ok(MappedKeys::Default),
+                range => {
+                    let (start, end) = range.split_once("..").ok_or_else(invalid)?;
+                    let start: u32 = start.trim().parse().map_err(|_| invalid())?;
+                    let end: u32 = end.trim().parse().map_err(|_| invalid())?;
+                    match start <= end && end <= MAX_KEY {
+                        true => Ok(MappedKeys::Keys((start..=end).collect())),
+                        false => Err(invalid()),
+                    }
+                }
+            },
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Resolves what the mapping does. Exactly one of `script`, `type`,
+    /// `combo`, `run`, `layer` and inline `press`/`release` snippets must be
+    /// set.
+    pub fn action(&self) -> Result<MappingAction> {
+        let inline = match (&self.press, &self.release) {
+            (None, None) => None,
+            (press, release) => Some(MappingAction::Inline {
+                press: press.clone(),
+                release: release.clone(),
+            }),
+        };
+
+        let actions: Vec<MappingAction> = [
+            self.script.clone().map(MappingAction::Script),
+            self.text.clone().map(MappingAction::Text),
+            self.combo.clone().map(MappingAction::Combo),
+            self.run.clone().map(MappingAction::Run),
+            self.layer.clone().map(MappingAction::Layer),
+            inline,
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        match <[MappingAction; 1]>::try_from(actions) {
+            Ok([MappingAction::Run(command)]) if command.is_empty() => Err(Error::new(
+                InvalidMappingAction(String::from("run needs a command")),
+            )),
+            Ok([action]) => Ok(action),
+            Err(_) => Err(Error::new(InvalidMappingAction(String::from(
+                "set exactly one of script, type, combo, run, layer or press/release",
+            )))),
+        }
+    }
+}
+
+/// How a mapping reacts to its key. `Momentary` calls `Press`/`Release`,
+/// `Toggle` latches on alternate presses and calls `On`/`Off`.
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Momentary,
+    Toggle,
+}
+
+/// Auto-repeat settings for a mapping. All durations are in milliseconds.
+///
+/// While the key is held the script's `Repeat` function (or `Press` if the
+/// table has no `Repeat`) is called every `interval` ms after an initial
+/// `delay`. After each repeat the interval is multiplied by `acceleration`
+/// and clamped to `min_interval`.
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct Repeat {
+    #[serde(default = "default_repeat_delay")]
+    pub delay: u64,
+    #[serde(default = "default_repeat_interval")]
+    pub interval: u64,
+    #[serde(default = "default_repeat_acceleration")]
+    pub acceleration: f64,
+    #[serde(default = "default_repeat_min_interval")]
+    pub min_interval: u64,
+}
+
+fn default_repeat_delay() -> u64 {
+    500
+}
+
+fn default_repeat_interval() -> u64 {
+    100
+}
+
+fn default_repeat_acceleration() -> f64 {
+    1.0
+}
+
+fn default_repeat_min_interval() -> u64 {
+    10
+}
+
+/// A leader-key sequence: pressing `keys` in order, each within
+/// `sequence_timeout` of the last, calls the script's `Press` function.
+#[derive(Deserialize, PartialEq, Debug)]
+pub struct Sequence {
+    pub keys: Vec<u32>,
+    pub script: String,
+}
+
+/// A cron-style schedule (`minute hour day-of-month month day-of-week`) on
+/// which the script's `Scheduled` function is called.
+#[derive(Deserialize, PartialEq, Debug)]
+pub struct Schedule {
+    pub cron: String,
     pub script: String,
 }
 
+/// Capabilities granted to a script, keyed by script name in the config's
+/// `permissions` table. Scripts without an entry may send keyboard and mouse
+/// output and drive LEDs but nothing else.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(default)]
+pub struct Permissions {
+    pub keyboard: bool,
+    pub mouse: bool,
+    pub leds: bool,
+    pub exec: bool,
+    pub filesystem: Vec<String>,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Self {
+            keyboard: true,
+            mouse: true,
+            leds: true,
+            exec: false,
+            filesystem: vec![],
+        }
+    }
+}
+
+/// Limits applied to every handler invocation, guarding against runaway
+/// scripts. `time` is wall-clock milliseconds per run of a handler (time spent
+/// in `sleep` and other waits doesn't count), `instructions` is the number of
+/// Lua VM instructions per run, and `memory` is the size of the whole Lua
+/// state in megabytes. A value of `0` disables that limit.
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct Limits {
+    pub time: u64,
+    pub instructions: u64,
+    pub memory: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            time: 5000,
+            instructions: 0,
+            memory: 0,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Config {
     #[serde(default = "default_log_level")]
     pub log_level: LevelFilter,
     pub device: Devices,
     pub mappings: Vec<Mapping>,
+    #[serde(default)]
+    pub sequences: Vec<Sequence>,
+    #[serde(default = "default_sequence_timeout")]
+    pub sequence_timeout: u64,
+    #[serde(default)]
+    pub sequence_cancel: Option<u32>,
+    #[serde(default)]
+    pub schedules: Vec<Schedule>,
+    #[serde(default)]
+    pub permissions: HashMap<String, Permissions>,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub layers: HashMap<String, Layer>,
+    #[serde(default)]
+    pub scripts: Vec<String>,
+    #[serde(default)]
+    pub error_led: bool,
+    #[serde(skip)]
+    pub path: PathBuf,
+    #[serde(skip)]
+    text: String,
 }
 
 fn default_log_level() -> LevelFilter {
     LevelFilter::Info
 }
 
+fn default_sequence_timeout() -> u64 {
+    1000
+}
+
 impl Config {
     pub fn new(path: &Path) -> Result<Arc<Mutex<Self>>> {
-        let config_text = fs::read_to_string(path)?;
-        let config = Arc::new(Mutex::new(toml::from_str(&config_text)?));
+        let config = Arc::new(Mutex::new(Self::read(path)?));
 
         Ok(config)
     }
 
+    fn read(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut config: Config = match path.extension() == Some("lua".as_ref()) {
+            true => lua::evaluate(path, &text)?,
+            false => toml::from_str(&text)?,
+        };
+        config.path = PathBuf::from(path);
+        config.text = text;
+
+        Ok(config)
+    }
+
+    /// Whether the config comes from an `init.lua`, which is also loaded as a
+    /// script for the handlers it registers.
+    pub fn is_lua(&self) -> bool {
+        self.path.extension() == Some("lua".as_ref())
+    }
+
+    /// Where every inline snippet sits in the config file. `Spanned` compares
+    /// equal regardless of position, but a snippet that moved has to be
+    /// reloaded for its errors to name the right line.
+    fn snippet_spans(&self) -> Vec<Range<usize>> {
+        let mut layers: Vec<(&String, &Layer)> = self.layers.iter().collect();
+        layers.sort_by_key(|(name, _)| *name);
+
+        layers
+            .into_iter()
+            .flat_map(|(_, layer)| &layer.mappings)
+            .chain(&self.mappings)
+            .flat_map(|mapping| [&mapping.press, &mapping.release])
+            .flatten()
+            .map(|snippet| snippet.span())
+            .collect()
+    }
+
+    /// The line of the config file an inline snippet's code starts on. A
+    /// multi-line string starting with a newline starts on the next line.
+    pub fn snippet_line(&self, snippet: &Spanned<String>) -> usize {
+        let start = snippet.span().start;
+        let (before, rest) = self.text.split_at(start.min(self.text.len()));
+        let line = before.matches('\n').count() + 1;
+
+        let multi_line = rest.starts_with("'''") || rest.starts_with("\"\"\"");
+        match multi_line && rest[3..].starts_with(['\n', '\r']) {
+            true => line + 1,
+            false => line,
+        }
+    }
+
     pub async fn update(&mut self) -> Result<Vec<ConfigEvent>> {
         let path = match find_config() {
             Some(full_path) => full_path,
@@ -65,13 +381,25 @@ impl Config {
 
         let mut config_events = vec![];
 
-        let config: Config = toml::from_str(&fs::read_to_string(path)?)?;
+        let config = Self::read(&path)?;
 
         if config.device != self.device {
             config_events.push(ConfigEvent::Device);
         }
 
-        if !config.mappings.eq(&self.mappings) {
+        if !config.mappings.eq(&self.mappings)
+            || !config.sequences.eq(&self.sequences)
+            || config.sequence_timeout != self.sequence_timeout
+            || config.sequence_cancel != self.sequence_cancel
+            || !config.schedules.eq(&self.schedules)
+            || !config.permissions.eq(&self.permissions)
+            || config.limits != self.limits
+            || !config.layers.eq(&self.layers)
+            || !config.scripts.eq(&self.scripts)
+            || config.error_led != self.error_led
+            || config.snippet_spans() != self.snippet_spans()
+            || (config.is_lua() && config.text != self.text)
+        {
             config_events.push(ConfigEvent::Mapping);
         }
 
@@ -105,9 +433,15 @@ impl ConfigWatcher {
             },
         )?;
 
-        tokio::spawn(config_watcher(config.clone(), rx, ce_tx.clone()));
+        let file_name = PathBuf::from(path.file_name().unwrap_or_default());
+        tokio::spawn(config_watcher(config.clone(), file_name, rx, ce_tx.clone()));
 
-        watcher.watch(&path, RecursiveMode::NonRecursive)?;
+        // The directory is watched so saves that replace the file are seen.
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
 
         Ok(Self {
             config,
@@ -133,34 +467,139 @@ fn find_config() -> Option<PathBuf> {
     None
 }
 
+/// Updates the config when its file changes. Like the script watcher, it
+/// waits for a burst of events from one save to settle before reading it.
 async fn config_watcher(
     config: Arc<Mutex<Config>>,
+    file_name: PathBuf,
     mut rx: Receiver<Result<Event, NotifyError>>,
     tx: Sender<ConfigEvent>,
 ) {
-    loop {
-        if let Some(Ok(event)) = rx.recv().await {
-            if event.kind
-                == notify::EventKind::Modify(notify::event::ModifyKind::Data(
-                    notify::event::DataChange::Content,
-                ))
-            {
-                let mut conf = config.lock().await;
-                if let Ok(events) = conf.update().await {
-                    info!("Updating Config file");
-                    for event in events {
-                        info!("Config update event: {:?}", event);
-                        if tx.send(event).is_err() {
-                            error!("Cannot send Config update event to broadcast channel");
-                        }
+    while let Some(result) = rx.recv().await {
+        let mut changed = false;
+        let mut next = Some(result);
+
+        while let Some(result) = next {
+            match result {
+                Ok(event) if is_file_change(&event) => {
+                    changed |= event
+                        .paths
+                        .iter()
+                        .any(|path| path.file_name() == Some(file_name.as_os_str()));
+                }
+                Ok(event) => debug!(
+                    "Config file notify fired but not an update notification: {:?}",
+                    event
+                ),
+                Err(e) => error!("Config watcher error: {}", e),
+            }
+            next = timeout(WATCH_DEBOUNCE, rx.recv()).await.ok().flatten();
+        }
+
+        if !changed {
+            continue;
+        }
+
+        let mut conf = config.lock().await;
+        match conf.update().await {
+            Ok(events) => {
+                info!("Updating Config file");
+                for event in events {
+                    info!("Config update event: {:?}", event);
+                    if tx.send(event).is_err() {
+                        error!("Cannot send Config update event to broadcast channel");
                     }
-                } else {
-                    debug!(
-                        "Config file notify fired but not an update notification: {:?}",
-                        event
-                    );
                 }
             }
+            Err(e) => error!("Couldn't update config: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mapping(keys: &str) -> Mapping {
+        toml::from_str(&format!("{}\nscript = 'Test.lua'", keys)).unwrap()
+    }
+
+    #[test]
+    fn test_mapped_keys() {
+        assert_eq!(
+            mapping("key = 3").mapped_keys().unwrap(),
+            MappedKeys::Keys(vec![3])
+        );
+        assert_eq!(
+            mapping("keys = [0, 1, 2]").mapped_keys().unwrap(),
+            MappedKeys::Keys(vec![0, 1, 2])
+        );
+        assert_eq!(
+            mapping("keys = '4..7'").mapped_keys().unwrap(),
+            MappedKeys::Keys(vec![4, 5, 6, 7])
+        );
+        assert_eq!(
+            mapping("keys = '*'").mapped_keys().unwrap(),
+            MappedKeys::Default
+        );
+
+        for invalid in [
+            "",
+            "key = 1\nkeys = [2]",
+            "keys = []",
+            "keys = '7..4'",
+            "keys = 'a..b'",
+            "keys = '0..4294967295'",
+            "keys = [0, 65536]",
+            "key = 4294967295",
+        ] {
+            assert!(mapping(invalid).mapped_keys().is_err(), "{}", invalid);
         }
     }
+
+    #[test]
+    fn test_action() {
+        let action = |fields: &str| toml::from_str::<Mapping>(fields).unwrap().action();
+
+        assert_eq!(
+            action("script = 'Test.lua'").unwrap(),
+            MappingAction::Script(String::from("Test.lua"))
+        );
+        assert_eq!(
+            action("type = 'Regards,'").unwrap(),
+            MappingAction::Text(String::from("Regards,"))
+        );
+        assert_eq!(
+            action("run = ['code', '.']").unwrap(),
+            MappingAction::Run(vec![String::from("code"), String::from(".")])
+        );
+        assert_eq!(
+            action("layer = 'video'").unwrap(),
+            MappingAction::Layer(String::from("video"))
+        );
+
+        assert!(action("").is_err());
+        assert!(action("run = []").is_err());
+        assert!(action("script = 'Test.lua'\ncombo = 'Ctrl+C'").is_err());
+        assert!(action("script = 'Test.lua'\nrelease = 'x()'").is_err());
+        assert!(matches!(
+            action("press = 'x()'").unwrap(),
+            MappingAction::Inline {
+                press: Some(_),
+                release: None
+            }
+        ));
+    }
+
+    #[test]
+    fn test_snippet_line() {
+        let text =
+            "device = 'Dummy'\n\n[[mappings]]\nkey = 0\npress = 'a()'\nrelease = '''\nb()\n'''\n";
+        let mut config: Config = toml::from_str(text).unwrap();
+        config.text = String::from(text);
+
+        let mapping = &config.mappings[0];
+        assert_eq!(config.snippet_line(mapping.press.as_ref().unwrap()), 5);
+        assert_eq!(config.snippet_line(mapping.release.as_ref().unwrap()), 7);
+    }
 }
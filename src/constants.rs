@@ -1,7 +1,35 @@
-pub static CONFIG_FILE_NAMES: [&str; 2] = ["scriptkeys.toml", "config.toml"];
+use std::time::Duration;
+
+pub static CONFIG_FILE_NAMES: [&str; 3] = ["scriptkeys.toml", "config.toml", "init.lua"];
 pub static CONFIG_FILE_PATHS: [&str; 3] = ["./", "$HOME/.scriptkeys/", "$HOME/.config/"];
 
 pub static SCRIPT_FILE_PATHS: [&str; 2] = ["./.scripts/", "$HOME/.scriptkeys/scripts/"];
 
 pub static LOG_FILE_NAMES: [&str; 1] = ["scriptkeys.log"];
 pub static LOG_FILE_PATHS: [&str; 2] = ["$HOME/.scriptkeys/", "./"];
+
+pub static STATE_FILE_PATHS: [&str; 2] = ["$HOME/.scriptkeys/", "./"];
+pub static TOGGLE_FILE_NAME: &str = "toggles.toml";
+pub static STORE_FILE_NAME: &str = "store.toml";
+pub static STATUS_FILE_NAME: &str = "status.toml";
+
+/// How long a watched file has to be quiet before it's reloaded.
+pub static WATCH_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// The shortest time between writes of the script store file.
+pub static STORE_SAVE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The shortest time between writes of the script status file.
+pub static STATUS_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a key's red LED flashes after its handler fails.
+pub static ERROR_LED_DURATION: Duration = Duration::from_secs(2);
+
+/// The highest key number a mapping may use.
+pub static MAX_KEY: u32 = u16::MAX as u32;
+
+/// How many bytes of each output stream `exec` keeps for its result.
+pub static MAX_PROCESS_OUTPUT: usize = 1024 * 1024;
+
+/// The shortest interval `setInterval` runs a callback at.
+pub static MIN_TIMER_INTERVAL: Duration = Duration::from_millis(10);
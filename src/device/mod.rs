@@ -2,26 +2,110 @@ pub mod xkeys;
 
 use std::{thread, time::Duration};
 
-use {anyhow::Result, serde::Deserialize, tokio::sync::mpsc::Sender};
+use log::{error, trace};
 
-#[derive(Deserialize, Debug, PartialEq)]
+use {
+    anyhow::Result,
+    serde::Deserialize,
+    tokio::sync::mpsc::{Receiver, Sender},
+};
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum Action {
     Press,
     Release,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Event {
     pub key: u32,
     pub action: Action,
 }
 
-#[derive(Deserialize, PartialEq, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceStatus {
+    Connected,
+    Disconnected,
+    /// The joystick moved to a new position.
+    Joystick(Joystick),
+}
+
+/// How far a joystick is pushed along each axis, as the signed value the
+/// device reports. `z` is the twist; zero on every axis is centred.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Joystick {
+    pub x: i8,
+    pub y: i8,
+    pub z: i8,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Led {
+    Blue,
+    Red,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LedState {
+    Off,
+    On,
+    Flash,
+}
+
+#[derive(Debug)]
+pub enum DeviceCommand {
+    Backlight {
+        key: u32,
+        led: Led,
+        state: LedState,
+    },
+    /// Shows `state` without replacing the state the LED was set to, which
+    /// `RestoreBacklight` puts back.
+    TemporaryBacklight {
+        key: u32,
+        led: Led,
+        state: LedState,
+    },
+    RestoreBacklight {
+        key: u32,
+        led: Led,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
 pub enum Devices {
     XK68JS,
     Dummy,
 }
 
+impl Devices {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Devices::XK68JS => "XK68JS",
+            Devices::Dummy => "Dummy",
+        }
+    }
+
+    /// Row and column of `key`, for devices laid out as a grid.
+    pub fn position(&self, key: u32) -> Option<(u32, u32)> {
+        match self {
+            Devices::XK68JS => xkeys::xk68js::XK68JS::position(key),
+            Devices::Dummy => None,
+        }
+    }
+
+    /// Whether the device has a key numbered `key`. The dummy device has no
+    /// layout, so it accepts any key.
+    pub fn has_key(&self, key: u32) -> bool {
+        match self {
+            Devices::Dummy => true,
+            _ => self.position(key).is_some(),
+        }
+    }
+}
+
 pub fn derive_device(device: &Devices) -> Result<Box<dyn Device + Send>> {
     match device {
         Devices::XK68JS => Ok(Box::<xkeys::xk68js::XK68JS>::default()),
@@ -32,7 +116,19 @@ pub fn derive_device(device: &Devices) -> Result<Box<dyn Device + Send>> {
 }
 
 pub trait Device {
-    fn read_loop(&mut self, tx: Sender<Event>);
+    fn read_loop(
+        &mut self,
+        tx: Sender<Event>,
+        status_tx: Sender<DeviceStatus>,
+        rx: Receiver<DeviceCommand>,
+    );
+}
+
+/// Reports a connection change from a device's blocking read loop.
+pub fn send_status(status_tx: &Sender<DeviceStatus>, status: DeviceStatus) {
+    if let Err(e) = status_tx.blocking_send(status) {
+        error!("Unable to send value into device status channel: {}", e);
+    }
 }
 
 pub struct Dummy {
@@ -40,8 +136,19 @@ pub struct Dummy {
 }
 
 impl Device for Dummy {
-    fn read_loop(&mut self, tx: Sender<Event>) {
+    fn read_loop(
+        &mut self,
+        tx: Sender<Event>,
+        status_tx: Sender<DeviceStatus>,
+        mut rx: Receiver<DeviceCommand>,
+    ) {
+        send_status(&status_tx, DeviceStatus::Connected);
+
         loop {
+            while let Ok(command) = rx.try_recv() {
+                trace!("Dummy device ignoring command: {:?}", command);
+            }
+
             let txc = tx.clone();
             tokio::spawn(async move {
                 let event = Event {
@@ -55,4 +162,3 @@ impl Device for Dummy {
         }
     }
 }
-
@@ -12,9 +12,9 @@
 // 00000001 Column 8
 // 00000001 Column 9
 // 00000001 Column 10
-// 00000000 Different representation of rotor
-// 01000000 Different representation of rotor
-// 00000000 Different representation of rotor
+// 00000000 Joystick X, signed
+// 01000000 Joystick Y, signed
+// 00000000 Joystick Z (twist), signed
 // 00000000 Different representation of rotor
 // 00000000 Continuous Inactive 00000001 Continuous Right 11111111 Continuous Left
 // 11111001 Rotor Left 00000111 Rotor Right
@@ -32,15 +32,26 @@ use {
     hidapi::{HidApi, HidDevice},
     log::{error, info, trace},
     serde::Deserialize,
-    tokio::sync::mpsc::Sender,
+    tokio::sync::mpsc::{Receiver, Sender},
 };
 
 use crate::{
-    device::{Action, Device, Event},
-    errors::DeviceNotFound,
+    device::{
+        send_status, Action, Device, DeviceCommand, DeviceStatus, Event, Joystick, Led, LedState,
+    },
+    errors::{DeviceNotFound, InvalidKey},
 };
 
 const MAX_BACKOFF: u64 = 60;
+const READ_TIMEOUT_MS: i32 = 50;
+const REPORT_LENGTH: usize = 36;
+const SET_BACKLIGHT: u8 = 181;
+const RED_BANK_OFFSET: u32 = 80;
+const ROWS: u32 = 8;
+const COLUMNS: u32 = 10;
+const JOYSTICK_X: usize = 12;
+const JOYSTICK_Y: usize = 13;
+const JOYSTICK_Z: usize = 14;
 
 #[derive(Debug)]
 pub struct State {}
@@ -60,6 +71,10 @@ impl Default for InterfaceType {
 #[derive(Deserialize)]
 pub struct XK68JS {
     pub state: HashMap<u32, InterfaceType>,
+    #[serde(skip)]
+    backlights: HashMap<(u32, Led), LedState>,
+    #[serde(skip)]
+    joystick: Joystick,
 }
 
 impl Default for XK68JS {
@@ -73,11 +88,24 @@ impl Default for XK68JS {
                 state.insert(id, InterfaceType::Button(false));
             }
         }
-        Self { state }
+        Self {
+            state,
+            backlights: HashMap::new(),
+            joystick: Joystick::default(),
+        }
     }
 }
 
 impl XK68JS {
+    /// Row and column of a key, both counted from zero at the top left. Keys
+    /// are numbered down each column in turn.
+    pub fn position(key: u32) -> Option<(u32, u32)> {
+        match key < ROWS * COLUMNS {
+            true => Some((key % ROWS, key / ROWS)),
+            false => None,
+        }
+    }
+
     pub fn process_buffer(&mut self, data: &[u8]) -> Vec<Event> {
         let mut change_buffer = vec![];
 
@@ -116,10 +144,56 @@ impl XK68JS {
         change_buffer
     }
 
+    /// The joystick's position if it moved since the last report.
+    pub fn process_joystick(&mut self, data: &[u8]) -> Option<Joystick> {
+        let joystick = Joystick {
+            x: data[JOYSTICK_X] as i8,
+            y: data[JOYSTICK_Y] as i8,
+            z: data[JOYSTICK_Z] as i8,
+        };
+
+        match joystick != self.joystick {
+            true => {
+                self.joystick = joystick;
+                Some(joystick)
+            }
+            false => None,
+        }
+    }
+
     fn bit_set(byte: u8, bit: i8) -> bool {
         (byte & (1 << bit)) != 0
     }
 
+    fn write_backlight(
+        device: &HidDevice,
+        key: u32,
+        led: Led,
+        state: LedState,
+    ) -> Result<(), Error> {
+        // Past the last key the index would land in the red bank, or wrap.
+        if Self::position(key).is_none() {
+            return Err(Error::new(InvalidKey(key)));
+        }
+        let index = match led {
+            Led::Blue => key,
+            Led::Red => key + RED_BANK_OFFSET,
+        };
+        let state = match state {
+            LedState::Off => 0,
+            LedState::On => 1,
+            LedState::Flash => 2,
+        };
+
+        let mut report = [0; REPORT_LENGTH];
+        report[1] = SET_BACKLIGHT;
+        report[2] = index as u8;
+        report[3] = state;
+        device.write(&report)?;
+
+        Ok(())
+    }
+
     fn get_device(&self) -> Result<HidDevice, Error> {
         let api = HidApi::new()?;
         let mut xkeys_device = None;
@@ -144,7 +218,12 @@ impl XK68JS {
 }
 
 impl Device for XK68JS {
-    fn read_loop(&mut self, tx: Sender<Event>) {
+    fn read_loop(
+        &mut self,
+        tx: Sender<Event>,
+        status_tx: Sender<DeviceStatus>,
+        mut rx: Receiver<DeviceCommand>,
+    ) {
         let mut device = None;
         let mut backoff = 1;
 
@@ -159,6 +238,12 @@ impl Device for XK68JS {
                     device = match self.get_device() {
                         Ok(dev) => {
                             info!("Connection to device established");
+                            for (&(key, led), &state) in &self.backlights {
+                                if let Err(e) = Self::write_backlight(&dev, key, led, state) {
+                                    error!("Couldn't restore backlight on device: {}", e);
+                                }
+                            }
+                            send_status(&status_tx, DeviceStatus::Connected);
                             Some(dev)
                         }
                         Err(e) => {
@@ -170,13 +255,40 @@ impl Device for XK68JS {
                 }
             };
 
-            if let Err(e) = dev.read(&mut buf) {
-                error!("Couldn't read from device: {}", e);
-                device = None;
-                continue;
+            while let Ok(command) = rx.try_recv() {
+                trace!("Device command: {:?}", command);
+                let (key, led, state) = match command {
+                    DeviceCommand::Backlight { key, led, state } => {
+                        self.backlights.insert((key, led), state);
+                        (key, led, state)
+                    }
+                    DeviceCommand::TemporaryBacklight { key, led, state } => (key, led, state),
+                    DeviceCommand::RestoreBacklight { key, led } => {
+                        let state = self.backlights.get(&(key, led)).copied();
+                        (key, led, state.unwrap_or(LedState::Off))
+                    }
+                };
+                if let Err(e) = Self::write_backlight(dev, key, led, state) {
+                    error!("Couldn't write backlight to device: {}", e);
+                }
+            }
+
+            match dev.read_timeout(&mut buf, READ_TIMEOUT_MS) {
+                Ok(0) => continue,
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Couldn't read from device: {}", e);
+                    send_status(&status_tx, DeviceStatus::Disconnected);
+                    device = None;
+                    continue;
+                }
             }
 
             let events = self.process_buffer(&buf);
+            if let Some(joystick) = self.process_joystick(&buf) {
+                trace!("Joystick moved: {:?}", joystick);
+                send_status(&status_tx, DeviceStatus::Joystick(joystick));
+            }
 
             let txc = tx.clone();
             tokio::spawn(async move {
@@ -217,4 +329,30 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_position() {
+        assert_eq!(XK68JS::position(0), Some((0, 0)));
+        assert_eq!(XK68JS::position(9), Some((1, 1)));
+        assert_eq!(XK68JS::position(79), Some((7, 9)));
+        assert_eq!(XK68JS::position(80), None);
+    }
+
+    #[test]
+    fn test_joystick() {
+        let mut data = vec![0; 64];
+        let mut device = XK68JS::default();
+        assert_eq!(device.process_joystick(&data), None);
+
+        data[JOYSTICK_X] = 0b00010100;
+        data[JOYSTICK_Y] = 0b11111011;
+        data[JOYSTICK_Z] = 0b10000001;
+        let expected = Joystick {
+            x: 20,
+            y: -5,
+            z: -127,
+        };
+        assert_eq!(device.process_joystick(&data), Some(expected));
+        assert_eq!(device.process_joystick(&data), None);
+    }
 }
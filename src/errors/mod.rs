@@ -15,6 +15,17 @@ impl Display for ConfigPathNotFound {
     }
 }
 
+#[derive(Debug)]
+pub struct LuaConfigNotTable;
+
+impl Error for LuaConfigNotTable {}
+
+impl Display for LuaConfigNotTable {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        formatter.write_str("init.lua must return the config table. Refer to the documentation.")
+    }
+}
+
 #[derive(Debug)]
 pub struct DeviceNotFound;
 
@@ -48,3 +59,74 @@ impl Display for LoadScriptError {
         formatter.write_str("Could not load script. Refer to the documentation.")
     }
 }
+
+#[derive(Debug)]
+pub struct InvalidMappingKeys(pub String);
+
+impl Error for InvalidMappingKeys {}
+
+impl Display for InvalidMappingKeys {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        write!(
+            formatter,
+            "Invalid keys for mapping to '{}'. Refer to the documentation.",
+            self.0
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigLoadFailed(pub usize);
+
+impl Error for ConfigLoadFailed {}
+
+impl Display for ConfigLoadFailed {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        write!(
+            formatter,
+            "Found {} problem(s) loading the config, the previous mappings remain active. Refer to the documentation.",
+            self.0
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidMappingAction(pub String);
+
+impl Error for InvalidMappingAction {}
+
+impl Display for InvalidMappingAction {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        write!(
+            formatter,
+            "Invalid mapping action: {}. Refer to the documentation.",
+            self.0
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidSchedule(pub String);
+
+impl Error for InvalidSchedule {}
+
+impl Display for InvalidSchedule {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        write!(
+            formatter,
+            "Invalid cron schedule '{}'. Refer to the documentation.",
+            self.0
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidKey(pub u32);
+
+impl Error for InvalidKey {}
+
+impl Display for InvalidKey {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        write!(formatter, "The device has no key {}.", self.0)
+    }
+}
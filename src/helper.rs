@@ -1,4 +1,17 @@
-use std::path::{Path, PathBuf};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use {
+    log::error,
+    mlua::{Function, Lua, Table},
+    notify::{event::ModifyKind, Event, EventKind},
+    tokio::{sync::watch, time::sleep},
+};
+
+use crate::constants::STATE_FILE_PATHS;
 
 pub fn parse_path(path: &str) -> PathBuf {
     let base = directories::BaseDirs::new().unwrap();
@@ -6,3 +19,127 @@ pub fn parse_path(path: &str) -> PathBuf {
 
     Path::new(&path).to_owned()
 }
+
+/// The first of the state directories that exists, where persisted state
+/// files are kept.
+pub fn find_state_dir() -> Option<PathBuf> {
+    STATE_FILE_PATHS
+        .iter()
+        .map(|path| parse_path(path))
+        .find(|path| path.is_dir())
+}
+
+/// Location for a persisted state file named `name` in the state directory.
+pub fn find_state_location(name: &str) -> Option<PathBuf> {
+    find_state_dir().map(|path| path.join(name))
+}
+
+/// Writes `contents` to a sibling temporary file and renames it over `path`
+/// so readers never observe a partially written file.
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Starts a task that writes whatever is sent to the returned channel to
+/// `path`, so callers don't wait on the disk. Contents sent while a write is
+/// in progress or within `interval` of the last one are coalesced, and only
+/// the latest is written.
+pub fn spawn_writer(
+    path: PathBuf,
+    interval: Duration,
+    what: &'static str,
+) -> watch::Sender<String> {
+    let (tx, mut rx) = watch::channel(String::new());
+
+    tokio::spawn(async move {
+        while rx.changed().await.is_ok() {
+            let contents = rx.borrow_and_update().clone();
+            if let Err(e) = write_atomic(&path, &contents) {
+                error!("Couldn't save {} ({}): {}", what, path.display(), e);
+            }
+            sleep(interval).await;
+        }
+    });
+
+    tx
+}
+
+/// Sets `hostname()` and `platform()` in `table`, for host-specific logic in
+/// `init.lua` and scripts.
+pub fn define_host(lua: &Lua, table: &Table) -> mlua::Result<()> {
+    let hostname = lua.create_function(|_lua, ()| {
+        Ok(gethostname::gethostname().to_string_lossy().into_owned())
+    })?;
+    table.set("hostname", hostname)?;
+
+    let platform = lua.create_function(|_lua, ()| Ok(std::env::consts::OS))?;
+    table.set("platform", platform)
+}
+
+/// `setmetatable` that refuses metatables with a `__gc` metamethod. Finalizers
+/// run whenever the collector gets to them, outside any handler and its
+/// limits, so a script could hang everything from one.
+const SETMETATABLE: &str = r#"
+local setmetatable, rawget, type, error = ...
+
+return function(table, metatable)
+    if type(metatable) == "table" and rawget(metatable, "__gc") ~= nil then
+        error("setmetatable: __gc metamethods aren't allowed", 2)
+    end
+    return setmetatable(table, metatable)
+end
+"#;
+
+/// Sets the restricted `setmetatable` in `table`.
+pub fn define_setmetatable(lua: &Lua, table: &Table) -> mlua::Result<()> {
+    let globals = lua.globals();
+    let setmetatable: Function = lua.load(SETMETATABLE).call((
+        globals.get::<_, Function>("setmetatable")?,
+        globals.get::<_, Function>("rawget")?,
+        globals.get::<_, Function>("type")?,
+        globals.get::<_, Function>("error")?,
+    ))?;
+    table.set("setmetatable", setmetatable)
+}
+
+/// Whether a watch event may have changed a file's contents: written in
+/// place, created, or renamed into place, as editors saving atomically do.
+pub fn is_file_change(event: &Event) -> bool {
+    match event.kind {
+        EventKind::Create(_) => true,
+        EventKind::Modify(ModifyKind::Metadata(_)) => false,
+        EventKind::Modify(_) => true,
+        _ => false,
+    }
+}
+
+/// A scratch directory for tests, unique to the test and removed when it's
+/// dropped.
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("scriptkeys-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self(fs::canonicalize(dir).unwrap())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
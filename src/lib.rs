@@ -5,9 +5,24 @@ pub mod errors;
 pub mod helper;
 pub mod script;
 
+/// A key pressed while holding `modifiers`, e.g. `Ctrl+Shift+T`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyChord {
+    pub modifiers: Vec<enigo::Key>,
+    pub key: enigo::Key,
+}
+
 #[derive(Debug)]
 pub enum EnigoCommand {
     KeyClick(enigo::Key),
     KeyDown(enigo::Key),
     KeyUp(enigo::Key),
+    Text { text: String, delay: Option<u64> },
+    Combo(Vec<KeyChord>),
+    MouseMoveTo(i32, i32),
+    MouseMoveRelative(i32, i32),
+    MouseClick(enigo::MouseButton),
+    MouseDown(enigo::MouseButton),
+    MouseUp(enigo::MouseButton),
+    MouseScroll(i32, i32),
 }
@@ -1,12 +1,14 @@
 use std::{
+    env,
     error::Error,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 
 use {
     anyhow::Result,
-    enigo::{Enigo, KeyboardControllable},
+    enigo::{Enigo, KeyboardControllable, MouseControllable},
     log::info,
     log::LevelFilter,
     log4rs::{
@@ -17,21 +19,33 @@ use {
     },
     tokio::{
         sync::{mpsc, Mutex},
-        task,
+        task, time,
     },
 };
 
 use scriptkeys::{
     config::{Config, ConfigWatcher},
     constants::{LOG_FILE_NAMES, LOG_FILE_PATHS},
-    device::{derive_device, Event},
-    script::{config_update_handler, script_loop, Script},
+    device::{derive_device, DeviceCommand, DeviceStatus, Event},
+    script::{config_update_handler, print_status, script_loop, status_loop, Script},
     EnigoCommand,
 };
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    match env::args().nth(1).as_deref() {
+        None => {}
+        Some("status") => return Ok(print_status()?),
+        Some(arg) => {
+            return Err(format!("Unknown argument: {}. Usage: scriptkeys [status]", arg).into())
+        }
+    }
+
     let (tx, rx): (mpsc::Sender<Event>, mpsc::Receiver<Event>) = mpsc::channel(32);
+    let (device_tx, device_rx): (mpsc::Sender<DeviceCommand>, mpsc::Receiver<DeviceCommand>) =
+        mpsc::channel(32);
+    let (status_tx, status_rx): (mpsc::Sender<DeviceStatus>, mpsc::Receiver<DeviceStatus>) =
+        mpsc::channel(32);
 
     let config_watcher = ConfigWatcher::new().await?;
 
@@ -40,7 +54,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         let mut device = derive_device(&conf.device)?;
 
         task::spawn_blocking(move || {
-            device.read_loop(tx);
+            device.read_loop(tx, status_tx, device_rx);
         });
     }
 
@@ -51,13 +65,18 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let (enigo_tx, mut enigo_rx): (mpsc::Sender<EnigoCommand>, mpsc::Receiver<EnigoCommand>) =
         mpsc::channel(32);
 
-    let script = Script::new(config_watcher.config.clone(), enigo_tx).await?;
+    let script = Script::new(config_watcher.config.clone(), enigo_tx, device_tx).await?;
 
     let script_clone = script.clone();
     task::spawn(async move {
         script_loop(script_clone, rx).await;
     });
 
+    let script_clone = script.clone();
+    task::spawn(async move {
+        status_loop(script_clone, status_rx).await;
+    });
+
     let script_clone = script.clone();
     task::spawn(async move {
         let config_event_reader = config_watcher.config_event.subscribe();
@@ -75,6 +94,42 @@ async fn main() -> Result<(), Box<dyn Error>> {
             EnigoCommand::KeyClick(key) => enigo.key_click(key),
             EnigoCommand::KeyDown(key) => enigo.key_down(key),
             EnigoCommand::KeyUp(key) => enigo.key_up(key),
+            EnigoCommand::Text { text, delay: None } => enigo.key_sequence(&text),
+            EnigoCommand::Text {
+                text,
+                delay: Some(delay),
+            } => {
+                // Later output waits for the text, so it's typed in the order
+                // it was sent.
+                for c in text.chars() {
+                    enigo.key_sequence(c.encode_utf8(&mut [0; 4]));
+                    time::sleep(Duration::from_millis(delay)).await;
+                }
+            }
+            EnigoCommand::MouseMoveTo(x, y) => enigo.mouse_move_to(x, y),
+            EnigoCommand::MouseMoveRelative(x, y) => enigo.mouse_move_relative(x, y),
+            EnigoCommand::MouseClick(button) => enigo.mouse_click(button),
+            EnigoCommand::MouseDown(button) => enigo.mouse_down(button),
+            EnigoCommand::MouseUp(button) => enigo.mouse_up(button),
+            EnigoCommand::MouseScroll(x, y) => {
+                if x != 0 {
+                    enigo.mouse_scroll_x(x);
+                }
+                if y != 0 {
+                    enigo.mouse_scroll_y(y);
+                }
+            }
+            EnigoCommand::Combo(chords) => {
+                for chord in chords {
+                    for modifier in &chord.modifiers {
+                        enigo.key_down(*modifier);
+                    }
+                    enigo.key_click(chord.key);
+                    for modifier in chord.modifiers.iter().rev() {
+                        enigo.key_up(*modifier);
+                    }
+                }
+            }
         }
     }
 
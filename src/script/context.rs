@@ -0,0 +1,73 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use mlua::{Lua, Table};
+
+use crate::{
+    device::{Devices, Joystick},
+    script::store::to_lua,
+};
+
+/// What triggered a handler. It's passed to the handler as its only argument,
+/// so one script can serve several keys.
+#[derive(Debug)]
+pub struct Context {
+    pub key: Option<u32>,
+    pub action: &'static str,
+    pub device: Devices,
+    pub timestamp: i64,
+    pub held_ms: Option<i64>,
+    pub params: Option<toml::Table>,
+    pub joystick: Option<Joystick>,
+}
+
+impl Context {
+    /// Context for an event that happens now. `pressed` is when the key went
+    /// down, for events that happen while or after it's held.
+    pub fn new(
+        device: Devices,
+        key: Option<u32>,
+        action: &'static str,
+        pressed: Option<Instant>,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as i64)
+            .unwrap_or_default();
+
+        Self {
+            key,
+            action,
+            device,
+            timestamp,
+            held_ms: pressed.map(|pressed| pressed.elapsed().as_millis() as i64),
+            params: None,
+            joystick: None,
+        }
+    }
+
+    pub fn to_table<'lua>(&self, lua: &'lua Lua) -> mlua::Result<Table<'lua>> {
+        let table = lua.create_table()?;
+        table.set("key", self.key)?;
+        table.set("action", self.action)?;
+        table.set("device", self.device.name())?;
+        table.set("timestamp", self.timestamp)?;
+        table.set("held_ms", self.held_ms)?;
+
+        if let Some((row, col)) = self.key.and_then(|key| self.device.position(key)) {
+            table.set("row", row)?;
+            table.set("col", col)?;
+        }
+
+        if let Some(joystick) = self.joystick {
+            table.set("x", joystick.x)?;
+            table.set("y", joystick.y)?;
+            table.set("z", joystick.z)?;
+        }
+
+        if let Some(params) = &self.params {
+            table.set("params", to_lua(lua, &toml::Value::Table(params.clone()))?)?;
+        }
+
+        Ok(table)
+    }
+}
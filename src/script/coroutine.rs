@@ -0,0 +1,429 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex as StdMutex, Weak},
+    time::Duration,
+};
+
+use {
+    log::{error, trace, warn},
+    mlua::{
+        Function, Lua, MultiValue, RegistryKey, Table, Thread, ThreadStatus, ToLuaMulti, Value,
+    },
+    tokio::{
+        sync::{mpsc::Sender, Mutex},
+        time::sleep,
+    },
+};
+
+use crate::{
+    constants::{ERROR_LED_DURATION, MAX_KEY},
+    device::{Action, DeviceCommand, Event, Led, LedState},
+    script::{
+        limits::Limiter,
+        process::{exec_task, Process},
+        status::Status,
+        Script,
+    },
+};
+
+const SLEEP: &str = "scriptkeys:sleep";
+const RELEASE: &str = "scriptkeys:release";
+const KEY: &str = "scriptkeys:key";
+const EXEC: &str = "scriptkeys:exec";
+
+/// Lua side of the waiting functions. They yield a tag back to the scheduler
+/// which parks the coroutine and releases the script lock until it's resumed.
+/// Arguments are checked here so a bad one is an error where it was passed.
+const PRELUDE: &str = r#"
+local max_key = ...
+local yield = coroutine.yield
+
+local function check_ms(name, ms)
+    if ms ~= nil and (type(ms) ~= "number" or not (ms >= 0)) then
+        error(name .. ": expected a non-negative number of milliseconds, got " .. tostring(ms), 3)
+    end
+end
+
+return {
+    sleep = function(ms)
+        check_ms("sleep", ms)
+        return yield("scriptkeys:sleep", ms)
+    end,
+    waitForRelease = function()
+        return yield("scriptkeys:release")
+    end,
+    waitForKey = function(key, timeout)
+        if math.type(key) ~= "integer" or key < 0 or key > max_key then
+            error("waitForKey: expected a key from 0 to " .. max_key .. ", got " .. tostring(key), 2)
+        end
+        check_ms("waitForKey", timeout)
+        return yield("scriptkeys:key", key, timeout)
+    end,
+}
+"#;
+
+enum Yield {
+    Sleep(u64),
+    Release,
+    Key(u32, Option<u64>),
+    Exec(Process),
+}
+
+#[derive(Debug, PartialEq)]
+enum Wait {
+    Running,
+    Sleep,
+    Release(u32),
+    Key(u32),
+    Exec,
+}
+
+struct Coroutine {
+    name: String,
+    /// The file the handler was defined in, which its errors are recorded
+    /// against.
+    source: String,
+    thread: RegistryKey,
+    key: Option<u32>,
+    wait: Wait,
+    ticket: u64,
+}
+
+/// Scheduler for handler coroutines. Every handler runs in its own Lua
+/// thread; when it waits the thread is parked here and resumed later under
+/// the script lock, so long running macros don't block other keys.
+pub struct Coroutines {
+    script: Weak<Mutex<Script>>,
+    limiter: Limiter,
+    next_id: u64,
+    running: HashMap<u64, Coroutine>,
+    held: HashSet<u32>,
+    status: Arc<StdMutex<Status>>,
+    device_tx: Sender<DeviceCommand>,
+    error_led: bool,
+}
+
+impl Coroutines {
+    pub fn new(
+        script: Weak<Mutex<Script>>,
+        limiter: Limiter,
+        status: Arc<StdMutex<Status>>,
+        device_tx: Sender<DeviceCommand>,
+    ) -> Self {
+        Self {
+            script,
+            limiter,
+            next_id: 0,
+            running: HashMap::new(),
+            held: HashSet::new(),
+            status,
+            device_tx,
+            error_led: false,
+        }
+    }
+
+    /// Whether a handler failing flashes the red LED of the key it was
+    /// handling.
+    pub fn set_error_led(&mut self, error_led: bool) {
+        self.error_led = error_led;
+    }
+
+    pub fn spawn<'lua, A: ToLuaMulti<'lua>>(
+        &mut self,
+        lua: &'lua Lua,
+        name: &str,
+        func: Function<'lua>,
+        key: Option<u32>,
+        args: A,
+    ) {
+        trace!("Executing script: {}", name);
+
+        let source = source(&func);
+        let result = lua
+            .create_thread(func)
+            .and_then(|thread| lua.create_registry_value(thread))
+            .and_then(|thread| Ok((thread, args.to_lua_multi(lua)?)));
+
+        let (thread, args) = match result {
+            Ok(result) => result,
+            Err(err) => {
+                error!("Failed to execute script ({}): {}", name, err);
+                self.status
+                    .lock()
+                    .unwrap()
+                    .failed(&source, name, &err.to_string());
+                return;
+            }
+        };
+
+        self.next_id += 1;
+        let id = self.next_id;
+        self.running.insert(
+            id,
+            Coroutine {
+                name: String::from(name),
+                source,
+                thread,
+                key,
+                wait: Wait::Running,
+                ticket: 0,
+            },
+        );
+
+        self.resume(lua, id, args);
+    }
+
+    /// Tracks held keys and resumes coroutines waiting on this key event.
+    pub fn key_event(&mut self, lua: &Lua, event: &Event) {
+        let wait = match event.action {
+            Action::Press => {
+                self.held.insert(event.key);
+                Wait::Key(event.key)
+            }
+            Action::Release => {
+                self.held.remove(&event.key);
+                Wait::Release(event.key)
+            }
+        };
+
+        let waiting: Vec<u64> = self
+            .running
+            .iter()
+            .filter(|(_, coroutine)| coroutine.wait == wait)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in waiting {
+            let args = match event.action {
+                Action::Press => MultiValue::from_vec(vec![Value::Boolean(true)]),
+                Action::Release => MultiValue::new(),
+            };
+            self.resume(lua, id, args);
+        }
+    }
+
+    /// Resumes a coroutine parked by a sleep, timeout or `exec`, as long as
+    /// it's still parked on the same wait.
+    pub fn wake<'lua>(&mut self, lua: &'lua Lua, id: u64, ticket: u64, args: MultiValue<'lua>) {
+        match self.running.get(&id) {
+            Some(coroutine) if coroutine.ticket == ticket && coroutine.wait != Wait::Running => {
+                self.resume(lua, id, args)
+            }
+            _ => {}
+        }
+    }
+
+    fn resume<'lua>(&mut self, lua: &'lua Lua, id: u64, mut args: MultiValue<'lua>) {
+        loop {
+            let coroutine = match self.running.get_mut(&id) {
+                Some(coroutine) => coroutine,
+                None => return,
+            };
+            coroutine.wait = Wait::Running;
+
+            let thread: Thread = match lua.registry_value(&coroutine.thread) {
+                Ok(thread) => thread,
+                Err(err) => {
+                    self.fail(lua, id, err);
+                    return;
+                }
+            };
+
+            self.limiter.start();
+            let result = thread.resume::<_, MultiValue>(args);
+            self.limiter.stop();
+
+            let values = match result {
+                Ok(values) => values,
+                Err(err) => {
+                    self.fail(lua, id, err);
+                    return;
+                }
+            };
+
+            if thread.status() != ThreadStatus::Resumable {
+                self.finish(lua, id);
+                return;
+            }
+
+            coroutine.ticket += 1;
+            let ticket = coroutine.ticket;
+
+            match parse_yield(values) {
+                Some(Yield::Sleep(ms)) => {
+                    coroutine.wait = Wait::Sleep;
+                    self.wake_after(id, ticket, ms, None);
+                }
+                Some(Yield::Release) => match coroutine.key {
+                    Some(key) if self.held.contains(&key) => coroutine.wait = Wait::Release(key),
+                    _ => {
+                        args = MultiValue::new();
+                        continue;
+                    }
+                },
+                Some(Yield::Key(key, timeout)) => {
+                    coroutine.wait = Wait::Key(key);
+                    if let Some(ms) = timeout {
+                        self.wake_after(id, ticket, ms, Some(false));
+                    }
+                }
+                Some(Yield::Exec(process)) => {
+                    coroutine.wait = Wait::Exec;
+                    tokio::spawn(exec_task(self.script.clone(), id, ticket, process));
+                }
+                None => {
+                    warn!(
+                        "Script yielded outside of sleep or wait ({}), resuming on next tick",
+                        coroutine.name
+                    );
+                    coroutine.wait = Wait::Sleep;
+                    self.wake_after(id, ticket, 0, None);
+                }
+            }
+
+            return;
+        }
+    }
+
+    fn wake_after(&self, id: u64, ticket: u64, ms: u64, value: Option<bool>) {
+        let script = self.script.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(ms)).await;
+
+            if let Some(script) = script.upgrade() {
+                let mut script = script.lock().await;
+                let script = &mut *script;
+                let args = match value {
+                    Some(value) => MultiValue::from_vec(vec![Value::Boolean(value)]),
+                    None => MultiValue::new(),
+                };
+                script.coroutines.wake(&script.lua, id, ticket, args);
+            }
+        });
+    }
+
+    /// Ends a coroutine that raised an error, recording it in the script's
+    /// status.
+    fn fail(&mut self, lua: &Lua, id: u64, err: mlua::Error) {
+        let coroutine = match self.running.get(&id) {
+            Some(coroutine) => coroutine,
+            None => return,
+        };
+        error!("Failed to execute script ({}): {}", coroutine.name, err);
+        self.status
+            .lock()
+            .unwrap()
+            .failed(&coroutine.source, &coroutine.name, &err.to_string());
+
+        if let (true, Some(key)) = (self.error_led, coroutine.key) {
+            self.flash_error(key);
+        }
+
+        self.finish(lua, id);
+    }
+
+    /// Flashes `key`'s red LED for a moment, then puts back whatever state
+    /// it was set to.
+    fn flash_error(&self, key: u32) {
+        let device_tx = self.device_tx.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let flash = DeviceCommand::TemporaryBacklight {
+                    key,
+                    led: Led::Red,
+                    state: LedState::Flash,
+                };
+                device_tx.send(flash).await?;
+                sleep(ERROR_LED_DURATION).await;
+                let restore = DeviceCommand::RestoreBacklight { key, led: Led::Red };
+                device_tx.send(restore).await
+            };
+            if let Err(e) = result.await {
+                error!("Unable to send value into device channel: {}", e);
+            }
+        });
+    }
+
+    fn finish(&mut self, lua: &Lua, id: u64) {
+        if let Some(coroutine) = self.running.remove(&id) {
+            if let Err(err) = lua.remove_registry_value(coroutine.thread) {
+                error!("Failed to release script ({}): {}", coroutine.name, err);
+            }
+        }
+    }
+}
+
+pub fn define_coroutines(lua: &Lua, api: &Table) -> anyhow::Result<()> {
+    let functions: Table = lua.load(PRELUDE).call(MAX_KEY)?;
+    for pair in functions.pairs::<String, Function>() {
+        let (name, func) = pair?;
+        api.set(name, func)?;
+    }
+
+    Ok(())
+}
+
+/// The file a function was defined in, from the name of its chunk.
+fn source(func: &Function) -> String {
+    let source = func.info().source.unwrap_or_default();
+    let source = String::from_utf8_lossy(&source);
+    String::from(source.strip_prefix('@').unwrap_or(&source))
+}
+
+fn parse_yield(values: MultiValue) -> Option<Yield> {
+    let values = values.into_vec();
+
+    let number = |index: usize| match values.get(index) {
+        Some(Value::Integer(value)) => u64::try_from(*value).ok(),
+        Some(Value::Number(value)) if *value >= 0.0 => Some(*value as u64),
+        _ => None,
+    };
+
+    let tag = match values.first() {
+        Some(Value::String(tag)) => tag.to_str().ok()?,
+        _ => return None,
+    };
+
+    match tag {
+        SLEEP => Some(Yield::Sleep(number(1).unwrap_or(0))),
+        RELEASE => Some(Yield::Release),
+        KEY => Some(Yield::Key(u32::try_from(number(1)?).ok()?, number(2))),
+        EXEC => match values.get(1) {
+            Some(Value::UserData(process)) => process.take().ok().map(Yield::Exec),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wait_arguments_are_checked() {
+        let lua = Lua::new();
+        define_coroutines(&lua, &lua.globals()).unwrap();
+
+        for (code, expected) in [
+            (
+                "waitForKey()",
+                "waitForKey: expected a key from 0 to 65535, got nil",
+            ),
+            ("waitForKey('a')", "got a"),
+            ("waitForKey(1.5)", "got 1.5"),
+            ("waitForKey(-1)", "got -1"),
+            ("waitForKey(65536)", "got 65536"),
+            (
+                "waitForKey(1, -5)",
+                "waitForKey: expected a non-negative number",
+            ),
+            ("waitForKey(1, 'soon')", "got soon"),
+            ("sleep(-1)", "sleep: expected a non-negative number"),
+            ("sleep(0/0)", "got "),
+        ] {
+            let err = lua.load(code).exec().unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", code, err);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::str::FromStr;
+
+use {
+    anyhow::{Error, Result},
+    chrono::{DateTime, Datelike, TimeZone, Timelike},
+};
+
+use crate::errors::InvalidSchedule;
+
+/// A parsed five field cron expression. Each field is a bit set of the values
+/// it matches. Fields accept `*`, single values, `a-b` ranges, `/step` and
+/// comma separated lists of those. Day of week runs from 0 (Sunday) to 6, with
+/// 7 accepted as Sunday as well.
+#[derive(Debug, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    day_of_month_any: bool,
+    day_of_week_any: bool,
+}
+
+impl Cron {
+    pub fn matches<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let day_of_month = bit(self.days_of_month, time.day());
+        let day_of_week = bit(self.days_of_week, time.weekday().num_days_from_sunday());
+
+        // Like cron, when both day fields are restricted either may match. A
+        // field starting with `*`, such as `*/2`, counts as unrestricted.
+        let day = match (self.day_of_month_any, self.day_of_week_any) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        };
+
+        day && bit(self.minutes, time.minute())
+            && bit(self.hours, time.hour())
+            && bit(self.months, time.month())
+    }
+}
+
+impl FromStr for Cron {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::new(InvalidSchedule(String::from(s)));
+
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(invalid());
+        }
+
+        let field = |index: usize, min: u32, max: u32| {
+            parse_field(fields[index], min, max).ok_or_else(invalid)
+        };
+
+        let mut days_of_week = field(4, 0, 7)?;
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week |= 1;
+        }
+
+        Ok(Self {
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days_of_month: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            days_of_week,
+            day_of_month_any: fields[2].starts_with('*'),
+            day_of_week_any: fields[4].starts_with('*'),
+        })
+    }
+}
+
+fn bit(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut set = 0;
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0)?),
+            None => (item, 1),
+        };
+
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+                None => {
+                    let value = range.parse().ok()?;
+                    (value, value)
+                }
+            },
+        };
+
+        if start < min || end > max || start > end {
+            return None;
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+
+    Some(set)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn test_cron() {
+        let cron: Cron = "*/15 9-17 * * 1-5".parse().unwrap();
+        // Monday 2024-01-01
+        assert!(cron.matches(&Utc.with_ymd_and_hms(2024, 1, 1, 9, 30, 0).unwrap()));
+        assert!(!cron.matches(&Utc.with_ymd_and_hms(2024, 1, 1, 9, 31, 0).unwrap()));
+        assert!(!cron.matches(&Utc.with_ymd_and_hms(2024, 1, 1, 18, 0, 0).unwrap()));
+        // Sunday 2024-01-07
+        assert!(!cron.matches(&Utc.with_ymd_and_hms(2024, 1, 7, 9, 30, 0).unwrap()));
+
+        let cron: Cron = "0 0 1 * 7".parse().unwrap();
+        assert!(cron.matches(&Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap()));
+        assert!(cron.matches(&Utc.with_ymd_and_hms(2024, 1, 7, 0, 0, 0).unwrap()));
+        assert!(!cron.matches(&Utc.with_ymd_and_hms(2024, 1, 8, 0, 0, 0).unwrap()));
+
+        // Odd days that are also Mondays, not either.
+        let cron: Cron = "0 0 */2 * 1".parse().unwrap();
+        assert!(cron.matches(&Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()));
+        assert!(!cron.matches(&Utc.with_ymd_and_hms(2024, 1, 3, 0, 0, 0).unwrap()));
+        assert!(!cron.matches(&Utc.with_ymd_and_hms(2024, 1, 8, 0, 0, 0).unwrap()));
+        assert!(cron.matches(&Utc.with_ymd_and_hms(2024, 1, 15, 0, 0, 0).unwrap()));
+
+        assert!("* * *".parse::<Cron>().is_err());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+    }
+}
@@ -0,0 +1,518 @@
+use std::{
+    fmt::{Display, Error as FmtError, Formatter},
+    path::{Path, PathBuf},
+};
+
+use {
+    log::warn,
+    mlua::{Lua, MultiValue, StdLib, Table, Value},
+};
+
+use crate::{
+    config::Permissions,
+    helper::{define_host, define_setmetatable, parse_path},
+};
+
+/// Standard libraries loaded into the Lua state. Scripts only ever see the
+/// subset of them copied into their environment by `create_env`.
+pub fn lua_libraries() -> StdLib {
+    StdLib::COROUTINE
+        | StdLib::IO
+        | StdLib::MATH
+        | StdLib::OS
+        | StdLib::STRING
+        | StdLib::TABLE
+        | StdLib::UTF8
+}
+
+const SAFE_GLOBALS: [&str; 18] = [
+    "_VERSION",
+    "assert",
+    "error",
+    "getmetatable",
+    "ipairs",
+    "next",
+    "pairs",
+    "pcall",
+    "print",
+    "rawequal",
+    "rawget",
+    "rawlen",
+    "rawset",
+    "select",
+    "tonumber",
+    "tostring",
+    "type",
+    "xpcall",
+];
+
+const SAFE_LIBRARIES: [&str; 5] = ["coroutine", "math", "string", "table", "utf8"];
+
+const SAFE_OS: [&str; 4] = ["clock", "date", "difftime", "time"];
+
+/// Wraps a function taking a path as its first argument so it raises an error
+/// unless `check` allows the path.
+const PATH_GUARD: &str = r#"
+local check, func, name = ...
+
+return function(path, ...)
+    if not check(path) then
+        error(name .. ": permission denied for path " .. tostring(path), 2)
+    end
+    return func(path, ...)
+end
+"#;
+
+/// Groups of API functions a script has to be granted in its `Permissions`.
+/// Each group is a table in the API table keyed by `name()`, mapping the
+/// (possibly dotted, e.g. `os.execute`) name the script sees to the function.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability {
+    Keyboard,
+    Mouse,
+    Leds,
+    Exec,
+    Filesystem,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 5] = [
+        Capability::Keyboard,
+        Capability::Mouse,
+        Capability::Leds,
+        Capability::Exec,
+        Capability::Filesystem,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Keyboard => "keyboard",
+            Capability::Mouse => "mouse",
+            Capability::Leds => "leds",
+            Capability::Exec => "exec",
+            Capability::Filesystem => "filesystem",
+        }
+    }
+
+    fn granted(&self, permissions: &Permissions) -> bool {
+        match self {
+            Capability::Keyboard => permissions.keyboard,
+            Capability::Mouse => permissions.mouse,
+            Capability::Leds => permissions.leds,
+            Capability::Exec => permissions.exec,
+            Capability::Filesystem => !permissions.filesystem.is_empty(),
+        }
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), FmtError> {
+        formatter.write_str(self.name())
+    }
+}
+
+/// Creates the API table: a `common` table of functions every script gets and
+/// one table per `Capability`. The standard library functions that need a
+/// capability are registered here as well.
+pub fn create_api(lua: &Lua) -> mlua::Result<Table<'_>> {
+    // Every string shares one metatable whose `__index` is the global
+    // `string` table, so scripts mustn't be able to reach it.
+    lua.load(r#"getmetatable("").__metatable = false"#).exec()?;
+
+    let globals = lua.globals();
+    let os: Table = globals.get("os")?;
+    let io: Table = globals.get("io")?;
+
+    let api = lua.create_table()?;
+
+    let common = lua.create_table()?;
+    common.set("shared", lua.create_table()?)?;
+    define_host(lua, &common)?;
+    define_setmetatable(lua, &common)?;
+    api.set("common", common)?;
+
+    for capability in Capability::ALL {
+        api.set(capability.name(), lua.create_table()?)?;
+    }
+
+    let exec: Table = api.get(Capability::Exec.name())?;
+    exec.set("os.execute", os.get::<_, Value>("execute")?)?;
+    exec.set("io.popen", io.get::<_, Value>("popen")?)?;
+
+    let filesystem: Table = api.get(Capability::Filesystem.name())?;
+    filesystem.set("io.open", io.get::<_, Value>("open")?)?;
+    filesystem.set("io.lines", io.get::<_, Value>("lines")?)?;
+
+    Ok(api)
+}
+
+/// Builds a fresh environment for a single script: the safe standard library,
+/// the common API, each capability's functions if `permissions` grants it, and
+/// `_G` pointing back at the environment itself. Library tables are copied so
+/// one script can't patch them for another; cross-script data goes through
+/// `shared`, which every environment references.
+///
+/// Functions of capabilities that aren't granted are replaced by stubs that
+/// log the denial and raise an error, rather than being left undefined.
+pub fn create_env<'lua>(
+    lua: &'lua Lua,
+    api: &Table<'lua>,
+    permissions: &Permissions,
+    script: &str,
+) -> mlua::Result<Table<'lua>> {
+    let globals = lua.globals();
+    let env = lua.create_table()?;
+
+    for name in SAFE_GLOBALS {
+        env.set(name, globals.get::<_, Value>(name)?)?;
+    }
+
+    for name in SAFE_LIBRARIES {
+        env.set(name, copy_table(lua, &globals.get(name)?, None)?)?;
+    }
+
+    env.set("os", copy_table(lua, &globals.get("os")?, Some(&SAFE_OS))?)?;
+    env.set("io", lua.create_table()?)?;
+
+    for pair in api.get::<_, Table>("common")?.pairs::<String, Value>() {
+        let (name, value) = pair?;
+        set_path(lua, &env, &name, value)?;
+    }
+
+    let roots: Vec<PathBuf> = permissions
+        .filesystem
+        .iter()
+        .filter_map(|root| parse_path(root).canonicalize().ok())
+        .collect();
+
+    for capability in Capability::ALL {
+        let functions: Table = api.get(capability.name())?;
+        let granted = capability.granted(permissions);
+
+        for pair in functions.pairs::<String, Value>() {
+            let (name, value) = pair?;
+            let value = match (granted, capability) {
+                (true, Capability::Filesystem) => guard_paths(lua, &name, value, roots.clone())?,
+                (true, _) => value,
+                (false, _) => deny(lua, &name, capability, script)?,
+            };
+            set_path(lua, &env, &name, value)?;
+        }
+    }
+
+    env.set("_G", env.clone())?;
+
+    Ok(env)
+}
+
+fn deny<'lua>(
+    lua: &'lua Lua,
+    name: &str,
+    capability: Capability,
+    script: &str,
+) -> mlua::Result<Value<'lua>> {
+    let (name, script) = (String::from(name), String::from(script));
+
+    let stub = lua.create_function(move |_lua, _: MultiValue| -> mlua::Result<()> {
+        warn!(
+            "Script {} denied {} capability calling {}",
+            script, capability, name
+        );
+        Err(mlua::Error::RuntimeError(format!(
+            "{}: permission denied, script lacks the {} capability",
+            name, capability
+        )))
+    })?;
+
+    Ok(Value::Function(stub))
+}
+
+fn guard_paths<'lua>(
+    lua: &'lua Lua,
+    name: &str,
+    func: Value<'lua>,
+    roots: Vec<PathBuf>,
+) -> mlua::Result<Value<'lua>> {
+    let check = lua.create_function(move |_lua, path: Value| {
+        Ok(match path {
+            Value::String(path) => within_roots(&roots, Path::new(path.to_str()?)),
+            _ => false,
+        })
+    })?;
+
+    lua.load(PATH_GUARD).call((check, func, name))
+}
+
+/// Whether `path`, which may not exist yet, resolves to somewhere inside one
+/// of the (canonical) `roots`.
+fn within_roots(roots: &[PathBuf], path: &Path) -> bool {
+    let resolved = match path.canonicalize() {
+        Ok(path) => Some(path),
+        // A dangling symlink can't be resolved, but opening it for writing
+        // would create its target wherever that is.
+        Err(_) if path.symlink_metadata().is_ok() => None,
+        Err(_) => match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => {
+                let parent = match parent.as_os_str().is_empty() {
+                    true => Path::new("."),
+                    false => parent,
+                };
+                parent.canonicalize().ok().map(|parent| parent.join(name))
+            }
+            _ => None,
+        },
+    };
+
+    match resolved {
+        Some(path) => roots.iter().any(|root| path.starts_with(root)),
+        None => false,
+    }
+}
+
+/// Sets `name` on `table`, descending into (and creating) sub-tables for
+/// dotted names such as `os.execute`.
+fn set_path<'lua>(
+    lua: &'lua Lua,
+    table: &Table<'lua>,
+    name: &str,
+    value: Value<'lua>,
+) -> mlua::Result<()> {
+    match name.split_once('.') {
+        Some((head, rest)) => {
+            let child = match table.get::<_, Option<Table>>(head)? {
+                Some(child) => child,
+                None => {
+                    let child = lua.create_table()?;
+                    table.set(head, child.clone())?;
+                    child
+                }
+            };
+            set_path(lua, &child, rest, value)
+        }
+        None => table.set(name, value),
+    }
+}
+
+fn copy_table<'lua>(
+    lua: &'lua Lua,
+    table: &Table<'lua>,
+    only: Option<&[&str]>,
+) -> mlua::Result<Table<'lua>> {
+    let copy = lua.create_table()?;
+
+    match only {
+        Some(names) => {
+            for name in names {
+                copy.set(*name, table.get::<_, Value>(*name)?)?;
+            }
+        }
+        None => {
+            for pair in table.clone().pairs::<Value, Value>() {
+                let (name, value) = pair?;
+                copy.set(name, value)?;
+            }
+        }
+    }
+
+    Ok(copy)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::helper::TempDir;
+    use std::{env, fs};
+
+    /// An `allowed` directory that's granted and a `denied` one beside it,
+    /// each holding a `file.txt`.
+    fn roots(dir: &TempDir) -> (PathBuf, PathBuf) {
+        let (allowed, denied) = (dir.path().join("allowed"), dir.path().join("denied"));
+        for root in [&allowed, &denied] {
+            fs::create_dir(root).unwrap();
+            fs::write(root.join("file.txt"), "contents").unwrap();
+        }
+        (allowed, denied)
+    }
+
+    #[test]
+    fn test_envs_are_isolated() {
+        let lua = Lua::new_with(lua_libraries(), mlua::LuaOptions::new()).unwrap();
+        let api = create_api(&lua).unwrap();
+        let permissions = Permissions::default();
+        let first = create_env(&lua, &api, &permissions, "First.lua").unwrap();
+        let second = create_env(&lua, &api, &permissions, "Second.lua").unwrap();
+
+        let patch = r#"
+            string.format = function() return "patched" end
+            local meta = getmetatable("")
+            if meta then meta.__index = { format = string.format } end
+            return meta
+        "#;
+        let meta: Value = lua
+            .load(patch)
+            .set_environment(first)
+            .unwrap()
+            .eval()
+            .unwrap();
+        assert_eq!(meta, Value::Boolean(false));
+
+        let check = r#"return string.format("%d", 1), ("%d"):format(2)"#;
+        let (copied, method): (String, String) = lua
+            .load(check)
+            .set_environment(second)
+            .unwrap()
+            .eval()
+            .unwrap();
+        assert_eq!((copied.as_str(), method.as_str()), ("1", "2"));
+    }
+
+    #[test]
+    fn test_finalizers_are_rejected() {
+        let lua = Lua::new_with(lua_libraries(), mlua::LuaOptions::new()).unwrap();
+        let api = create_api(&lua).unwrap();
+        let env = create_env(&lua, &api, &Permissions::default(), "Gc.lua").unwrap();
+        let run = |code: &str| lua.load(code).set_environment(env.clone())?.exec();
+
+        let err = run("setmetatable({}, { __gc = function() while true do end end })")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("__gc metamethods aren't allowed"), "{}", err);
+
+        // Other metatables still work, and a `__gc` added afterwards never
+        // marks the table for finalization.
+        run(r#"
+            local meta = { __index = { answer = 42 } }
+            local t = setmetatable({}, meta)
+            assert(t.answer == 42)
+            meta.__gc = function() while true do end end
+            t = nil
+        "#)
+        .unwrap();
+        lua.gc_collect().unwrap();
+    }
+
+    #[test]
+    fn test_within_roots() {
+        let dir = TempDir::new("within-roots");
+        let (allowed, denied) = roots(&dir);
+        let granted = [allowed.clone()];
+
+        assert!(within_roots(&granted, &allowed.join("file.txt")));
+        assert!(within_roots(&granted, &allowed.join("new.txt")));
+        assert!(!within_roots(&granted, &denied.join("file.txt")));
+        assert!(!within_roots(&granted, &denied.join("new.txt")));
+        assert!(!within_roots(&granted, &allowed.join("missing/new.txt")));
+        assert!(!within_roots(&granted, &allowed.join("../denied/file.txt")));
+        assert!(!within_roots(&granted, &allowed.join("../denied/new.txt")));
+        assert!(!within_roots(&[], &allowed.join("file.txt")));
+    }
+
+    #[test]
+    fn test_within_roots_relative() {
+        let cwd = env::current_dir().unwrap().canonicalize().unwrap();
+        let granted = [cwd.join("src")];
+
+        assert!(within_roots(&granted, Path::new("src/main.rs")));
+        assert!(within_roots(&granted, Path::new("src/new.rs")));
+        assert!(within_roots(&granted, Path::new("./src/../src/main.rs")));
+        assert!(!within_roots(&granted, Path::new("src/../Cargo.toml")));
+        assert!(!within_roots(&granted, Path::new("new.rs")));
+        assert!(within_roots(&[cwd], Path::new("new.rs")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_within_roots_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new("within-roots-symlinks");
+        let (allowed, denied) = roots(&dir);
+        symlink(&denied, allowed.join("dir")).unwrap();
+        symlink(denied.join("file.txt"), allowed.join("file")).unwrap();
+        symlink(denied.join("new.txt"), allowed.join("dangling")).unwrap();
+        symlink(allowed.join("file.txt"), denied.join("inside")).unwrap();
+        let granted = [allowed];
+
+        assert!(!within_roots(
+            &granted,
+            &dir.path().join("allowed/dir/file.txt")
+        ));
+        assert!(!within_roots(
+            &granted,
+            &dir.path().join("allowed/dir/new.txt")
+        ));
+        assert!(!within_roots(&granted, &dir.path().join("allowed/file")));
+        assert!(!within_roots(
+            &granted,
+            &dir.path().join("allowed/dangling")
+        ));
+        assert!(within_roots(&granted, &denied.join("inside")));
+    }
+
+    #[test]
+    fn test_filesystem_functions_are_guarded() {
+        let dir = TempDir::new("guard-paths");
+        let (allowed, denied) = roots(&dir);
+        let lua = Lua::new_with(lua_libraries(), mlua::LuaOptions::new()).unwrap();
+        let api = create_api(&lua).unwrap();
+        let permissions = Permissions {
+            filesystem: vec![allowed.display().to_string()],
+            ..Permissions::default()
+        };
+        let env = create_env(&lua, &api, &permissions, "Files.lua").unwrap();
+        env.set("allowed", allowed.display().to_string()).unwrap();
+        env.set("denied", denied.display().to_string()).unwrap();
+        let run = |code: &str| lua.load(code).set_environment(env.clone())?.exec();
+
+        run(r#"
+            local file = assert(io.open(allowed .. "/new.txt", "w"))
+            file:write("written")
+            file:close()
+            for line in io.lines(allowed .. "/file.txt") do assert(line == "contents") end
+        "#)
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(allowed.join("new.txt")).unwrap(),
+            "written"
+        );
+
+        for code in [
+            r#"io.open(denied .. "/file.txt")"#,
+            r#"io.open(denied .. "/new.txt", "w")"#,
+            r#"io.open(allowed .. "/../denied/file.txt")"#,
+            r#"io.lines(denied .. "/file.txt")"#,
+            "io.open(42)",
+        ] {
+            let err = run(code).unwrap_err().to_string();
+            assert!(
+                err.contains("permission denied for path"),
+                "{}: {}",
+                code,
+                err
+            );
+        }
+        assert!(!denied.join("new.txt").exists());
+    }
+
+    #[test]
+    fn test_denied_capabilities_are_stubbed() {
+        let lua = Lua::new_with(lua_libraries(), mlua::LuaOptions::new()).unwrap();
+        let api = create_api(&lua).unwrap();
+        let env = create_env(&lua, &api, &Permissions::default(), "Denied.lua").unwrap();
+        let run = |code: &str| lua.load(code).set_environment(env.clone())?.exec();
+
+        for (code, capability) in [
+            ("io.open('Cargo.toml')", "filesystem"),
+            ("io.lines('Cargo.toml')", "filesystem"),
+            ("os.execute('true')", "exec"),
+            ("io.popen('true')", "exec"),
+        ] {
+            let err = run(code).unwrap_err().to_string();
+            let expected = format!(
+                "permission denied, script lacks the {} capability",
+                capability
+            );
+            assert!(err.contains(&expected), "{}: {}", code, err);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::{collections::HashMap, path::Path};
+
+use mlua::{Function, Lua, RegistryKey, Table, Value};
+
+use crate::script::ScriptTable;
+
+/// Handler functions resolved from each script's table when the script is
+/// loaded, so dispatching an event is a direct call instead of a lookup
+/// through the script's environment.
+#[derive(Debug, Default)]
+pub struct Handlers {
+    tables: HashMap<ScriptTable, HashMap<String, RegistryKey>>,
+}
+
+impl Handlers {
+    /// Caches every function in `table`'s Lua table, replacing whatever was
+    /// cached for it before. Returns false if the environment doesn't define
+    /// the table at all.
+    pub fn resolve(&mut self, lua: &Lua, env: &Table, table: &ScriptTable) -> mlua::Result<bool> {
+        let mut methods = HashMap::new();
+
+        let found = match env.get::<_, Value>(table.name.as_str())? {
+            Value::Table(lua_table) => {
+                for pair in lua_table.pairs::<Value, Value>() {
+                    if let (Value::String(name), Value::Function(func)) = pair? {
+                        methods.insert(
+                            String::from(name.to_str()?),
+                            lua.create_registry_value(func)?,
+                        );
+                    }
+                }
+                true
+            }
+            _ => false,
+        };
+
+        if let Some(old) = self.tables.insert(table.clone(), methods) {
+            for (_, key) in old {
+                lua.remove_registry_value(key)?;
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Re-resolves every table cached for the script at `path` after it's
+    /// been reloaded into `env`.
+    pub fn refresh(&mut self, lua: &Lua, env: &Table, path: &Path) -> mlua::Result<()> {
+        let tables: Vec<ScriptTable> = self
+            .tables
+            .keys()
+            .filter(|table| table.path == path)
+            .cloned()
+            .collect();
+
+        for table in tables {
+            self.resolve(lua, env, &table)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get<'lua>(
+        &self,
+        lua: &'lua Lua,
+        table: &ScriptTable,
+        method: &str,
+    ) -> mlua::Result<Option<Function<'lua>>> {
+        match self
+            .tables
+            .get(table)
+            .and_then(|methods| methods.get(method))
+        {
+            Some(key) => lua.registry_value(key).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains(&self, table: &ScriptTable, method: &str) -> bool {
+        self.tables
+            .get(table)
+            .is_some_and(|methods| methods.contains_key(method))
+    }
+}
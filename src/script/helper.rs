@@ -9,10 +9,14 @@ use {
     objc2_foundation::NSPoint,
 };
 
-use enigo::Key;
+use enigo::{Key, MouseButton};
 
-pub fn map_str_to_key(s: &str) -> Key {
-    match s {
+use crate::KeyChord;
+
+/// Maps a key name, or a string holding a single character, to a `Key`.
+/// Returns `None` for anything else rather than guessing.
+pub fn map_str_to_key(s: &str) -> Option<Key> {
+    let key = match s {
         "Alt" => Key::Alt,
         "Backspace" => Key::Backspace,
         // "Begin" => Key::Begin,
@@ -96,7 +100,81 @@ pub fn map_str_to_key(s: &str) -> Key {
         "Tab" => Key::Tab,
         // "Undo" => Key::Undo,
         "UpArrow" => Key::UpArrow,
-        _ => Key::Layout(s.as_bytes()[0] as char),
+        _ => {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Key::Layout(c),
+                _ => return None,
+            }
+        }
+    };
+
+    Some(key)
+}
+
+pub fn map_str_to_button(s: &str) -> Option<MouseButton> {
+    match s {
+        "left" => Some(MouseButton::Left),
+        "middle" => Some(MouseButton::Middle),
+        "right" => Some(MouseButton::Right),
+        #[cfg(any(target_os = "windows", target_os = "linux"))]
+        "back" => Some(MouseButton::Back),
+        #[cfg(any(target_os = "windows", target_os = "linux"))]
+        "forward" => Some(MouseButton::Forward),
+        _ => None,
+    }
+}
+
+fn map_modifier(s: &str) -> Option<Key> {
+    match s.to_lowercase().as_str() {
+        "ctrl" | "control" => Some(Key::Control),
+        "shift" => Some(Key::Shift),
+        "alt" => Some(Key::Alt),
+        "option" | "opt" => Some(Key::Option),
+        "cmd" | "command" | "meta" | "super" | "win" => Some(Key::Meta),
+        _ => None,
+    }
+}
+
+/// Parses a shortcut such as `Ctrl+Shift+T`, or several separated by spaces
+/// such as `Ctrl+K Ctrl+C`. Every part but the last of a chord must be a
+/// modifier; the last may be any key `map_str_to_key` knows. Single letters
+/// are lowercased so `Ctrl+T` doesn't also imply Shift.
+pub fn parse_combo(s: &str) -> Result<Vec<KeyChord>, String> {
+    let mut chords = vec![];
+
+    for chord in s.split_whitespace() {
+        // A trailing "+" is the plus key itself, as in "Ctrl++".
+        let (rest, key) = match chord.strip_suffix("++") {
+            Some(rest) => (Some(rest), "+"),
+            None if chord == "+" => (None, "+"),
+            None => match chord.rsplit_once('+') {
+                Some((rest, key)) => (Some(rest), key),
+                None => (None, chord),
+            },
+        };
+
+        let mut modifiers = vec![];
+        for name in rest.into_iter().flat_map(|rest| rest.split('+')) {
+            match map_modifier(name) {
+                Some(modifier) => modifiers.push(modifier),
+                None => return Err(format!("{:?} in {:?} is not a modifier", name, chord)),
+            }
+        }
+
+        let key = match (map_modifier(key), key.chars().count()) {
+            (Some(modifier), _) => modifier,
+            (None, 1) => Key::Layout(key.chars().next().unwrap().to_ascii_lowercase()),
+            (None, _) => map_str_to_key(key)
+                .ok_or_else(|| format!("{:?} in {:?} is not a key", key, chord))?,
+        };
+
+        chords.push(KeyChord { modifiers, key });
+    }
+
+    match chords.is_empty() {
+        true => Err(String::from("empty key combo")),
+        false => Ok(chords),
     }
 }
 
@@ -129,3 +207,60 @@ pub fn hid_post_aux_key(key: u32, down: bool) -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_map_str_to_key() {
+        assert_eq!(map_str_to_key("Return"), Some(Key::Return));
+        assert_eq!(map_str_to_key("a"), Some(Key::Layout('a')));
+        assert_eq!(map_str_to_key("ü"), Some(Key::Layout('ü')));
+        assert_eq!(map_str_to_key("✓"), Some(Key::Layout('✓')));
+        assert_eq!(map_str_to_key(""), None);
+        assert_eq!(map_str_to_key("ab"), None);
+    }
+
+    #[test]
+    fn test_parse_combo() {
+        assert_eq!(
+            parse_combo("Ctrl+Shift+T"),
+            Ok(vec![KeyChord {
+                modifiers: vec![Key::Control, Key::Shift],
+                key: Key::Layout('t'),
+            }])
+        );
+        assert_eq!(
+            parse_combo("ctrl+k  Ctrl+Return"),
+            Ok(vec![
+                KeyChord {
+                    modifiers: vec![Key::Control],
+                    key: Key::Layout('k'),
+                },
+                KeyChord {
+                    modifiers: vec![Key::Control],
+                    key: Key::Return,
+                },
+            ])
+        );
+        assert_eq!(
+            parse_combo("Cmd++"),
+            Ok(vec![KeyChord {
+                modifiers: vec![Key::Meta],
+                key: Key::Layout('+'),
+            }])
+        );
+        assert_eq!(
+            parse_combo("F5"),
+            Ok(vec![KeyChord {
+                modifiers: vec![],
+                key: Key::F5,
+            }])
+        );
+        assert!(parse_combo("").is_err());
+        assert!(parse_combo("T+Ctrl").is_err());
+        assert!(parse_combo("Ctrl+Nope").is_err());
+        assert!(parse_combo("Ctrl+").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::{
+    config::MappedKeys,
+    script::{ScriptMapping, Target},
+};
+
+/// The mappings of one level of the config: the top level `mappings` or a
+/// layer's. Keys without a mapping of their own fall back to the default one.
+#[derive(Debug, Default)]
+pub struct KeyMap {
+    keys: HashMap<u32, ScriptMapping>,
+    default: Option<ScriptMapping>,
+}
+
+impl KeyMap {
+    /// Maps `keys` to `mapping`, returning the keys which were already
+    /// mapped. Later mappings win.
+    pub fn insert(&mut self, keys: MappedKeys, mapping: ScriptMapping) -> Vec<u32> {
+        match keys {
+            MappedKeys::Keys(keys) => keys
+                .into_iter()
+                .filter(|&key| self.keys.insert(key, mapping.clone()).is_some())
+                .collect(),
+            MappedKeys::Default => {
+                self.default = Some(mapping);
+                vec![]
+            }
+        }
+    }
+
+    pub fn get(&self, key: u32) -> Option<&ScriptMapping> {
+        self.keys.get(&key).or(self.default.as_ref())
+    }
+
+    fn mappings(&self) -> impl Iterator<Item = &ScriptMapping> {
+        self.keys.values().chain(self.default.iter())
+    }
+}
+
+/// The top level mappings and the layers over them. Active layers are looked
+/// at first, the most recently activated one first.
+#[derive(Debug, Default)]
+pub struct KeyMaps {
+    base: KeyMap,
+    layers: HashMap<String, KeyMap>,
+    active: Vec<String>,
+}
+
+impl KeyMaps {
+    /// Swaps in freshly loaded mappings. Layers that still exist stay active.
+    pub fn replace(&mut self, base: KeyMap, layers: HashMap<String, KeyMap>) {
+        self.base = base;
+        self.layers = layers;
+        self.active.retain(|name| self.layers.contains_key(name));
+    }
+
+    pub fn get(&self, key: u32) -> Option<&ScriptMapping> {
+        self.active
+            .iter()
+            .rev()
+            .filter_map(|name| self.layers.get(name))
+            .find_map(|layer| layer.get(key))
+            .or_else(|| self.base.get(key))
+    }
+
+    /// Paths of the scripts mapped anywhere, in the top level or a layer.
+    pub fn script_paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.layers
+            .values()
+            .chain([&self.base])
+            .flat_map(|key_map| key_map.mappings())
+            .filter_map(|mapping| match &mapping.target {
+                Target::Script(table) => Some(&table.path),
+                Target::Action(_) => None,
+            })
+    }
+
+    pub fn set_active(&mut self, name: &str, active: bool) {
+        self.active.retain(|active| active != name);
+        if active && self.layers.contains_key(name) {
+            self.active.push(String::from(name));
+        }
+    }
+}
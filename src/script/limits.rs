@@ -0,0 +1,193 @@
+use std::{
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
+};
+
+use mlua::{Function, HookTriggers, Lua, Table};
+
+use crate::config::Limits;
+
+/// Number of VM instructions between checks of the running handler's budget.
+const HOOK_INTERVAL: u32 = 1000;
+
+/// `pcall` and `xpcall` replacements that re-raise the error once the running
+/// handler is over its budget, so a script can't catch its way past a limit.
+const PROTECTED_CALLS: &str = r#"
+local exceeded, pcall, xpcall, error = ...
+
+local function check(ok, ...)
+    if not ok and exceeded() then
+        error((...), 0)
+    end
+    return ok, ...
+end
+
+return {
+    pcall = function(...)
+        return check(pcall(...))
+    end,
+    xpcall = function(...)
+        return check(xpcall(...))
+    end,
+}
+"#;
+
+#[derive(Debug, Default)]
+struct Budget {
+    limits: Limits,
+    started: Option<Instant>,
+    instructions: u64,
+    exceeded: bool,
+}
+
+/// Enforces `Limits` through a Lua instruction hook. Each run of Lua code is
+/// bracketed by `start`/`stop`; while a run is active the hook raises an error
+/// once it goes over its time or instruction budget, aborting the handler.
+#[derive(Clone, Debug, Default)]
+pub struct Limiter {
+    budget: Arc<StdMutex<Budget>>,
+}
+
+impl Limiter {
+    /// Installs the hook. Hooks are copied into new Lua threads when they are
+    /// created, so this must run before any handler coroutine exists.
+    pub fn install(&self, lua: &Lua) -> mlua::Result<()> {
+        let budget = self.budget.clone();
+
+        lua.set_hook(
+            HookTriggers {
+                every_nth_instruction: Some(HOOK_INTERVAL),
+                ..Default::default()
+            },
+            move |_lua, _debug| {
+                let mut budget = budget.lock().unwrap();
+                let started = match budget.started {
+                    Some(started) => started,
+                    None => return Ok(()),
+                };
+
+                budget.instructions += HOOK_INTERVAL as u64;
+
+                let limits = budget.limits;
+                if limits.instructions != 0 && budget.instructions > limits.instructions {
+                    budget.exceeded = true;
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "instruction limit exceeded ({} instructions)",
+                        limits.instructions
+                    )));
+                }
+
+                if limits.time != 0 && started.elapsed() > Duration::from_millis(limits.time) {
+                    budget.exceeded = true;
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "time limit exceeded ({} ms)",
+                        limits.time
+                    )));
+                }
+
+                Ok(())
+            },
+        )
+    }
+
+    /// Defines the limit-aware `pcall` and `xpcall` in the API's common table,
+    /// shadowing the standard ones in every script environment.
+    pub fn define(&self, lua: &Lua, common: &Table) -> anyhow::Result<()> {
+        let budget = self.budget.clone();
+        let exceeded = lua.create_function(move |_lua, ()| Ok(budget.lock().unwrap().exceeded))?;
+
+        let globals = lua.globals();
+        let functions: Table = lua.load(PROTECTED_CALLS).call((
+            exceeded,
+            globals.get::<_, Function>("pcall")?,
+            globals.get::<_, Function>("xpcall")?,
+            globals.get::<_, Function>("error")?,
+        ))?;
+
+        for pair in functions.pairs::<String, Function>() {
+            let (name, func) = pair?;
+            common.set(name, func)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.budget.lock().unwrap().limits
+    }
+
+    pub fn set_limits(&self, lua: &Lua, limits: Limits) -> mlua::Result<()> {
+        lua.set_memory_limit(limits.memory * 1024 * 1024)?;
+        self.budget.lock().unwrap().limits = limits;
+
+        Ok(())
+    }
+
+    pub fn start(&self) {
+        let mut budget = self.budget.lock().unwrap();
+        budget.started = Some(Instant::now());
+        budget.instructions = 0;
+        budget.exceeded = false;
+    }
+
+    pub fn stop(&self) {
+        self.budget.lock().unwrap().started = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Weak;
+
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+    use crate::script::{coroutine::Coroutines, status::Status};
+
+    #[test]
+    fn test_runaway_code_is_aborted() {
+        let lua = Lua::new();
+        let limiter = Limiter::default();
+        limiter.install(&lua).unwrap();
+        limiter.define(&lua, &lua.globals()).unwrap();
+
+        // A script's top-level code runs while it's loaded.
+        let limits = Limits {
+            time: 50,
+            instructions: 0,
+            memory: 0,
+        };
+        limiter.set_limits(&lua, limits).unwrap();
+        limiter.start();
+        let result = lua.load("while true do end").exec();
+        limiter.stop();
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("time limit exceeded"));
+
+        let limits = Limits {
+            time: 0,
+            instructions: 100_000,
+            memory: 0,
+        };
+        limiter.set_limits(&lua, limits).unwrap();
+        let (device_tx, _device_rx) = channel(1);
+        let status = Arc::new(StdMutex::new(Status::default()));
+        let mut coroutines = Coroutines::new(Weak::new(), limiter.clone(), status, device_tx);
+
+        let runaway: Function = lua
+            .load("function() pcall(function() while true do end end) finished = true end")
+            .eval()
+            .unwrap();
+        coroutines.spawn(&lua, "Runaway.Press", runaway, None, ());
+        assert_eq!(
+            lua.globals().get::<_, Option<bool>>("finished").unwrap(),
+            None
+        );
+
+        let later: Function = lua.load("function() later = true end").eval().unwrap();
+        coroutines.spawn(&lua, "Later.Press", later, None, ());
+        assert!(lua.globals().get::<_, bool>("later").unwrap());
+    }
+}
@@ -1,69 +1,294 @@
+mod context;
+mod coroutine;
+mod cron;
+mod environment;
+mod handler;
 mod helper;
+mod keymap;
+mod limits;
+mod module;
+mod process;
+mod sequence;
+mod status;
+mod store;
+mod subscription;
+mod timer;
+mod toggle;
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     fs,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex as StdMutex, Weak},
+    time::{Duration, Instant},
 };
 
 use {
     anyhow::{Error, Result},
-    enigo::Key,
-    log::{debug, error, info, trace},
-    mlua::Lua,
+    chrono::{Duration as ChronoDuration, Local, Timelike},
+    enigo::{Key, MouseButton},
+    log::{debug, error, info, trace, warn},
+    mlua::{Function, Lua, LuaOptions, RegistryKey, Table},
     notify::{
         Error as NotifyError, Event as NotifyEvent, RecommendedWatcher, RecursiveMode, Watcher,
     },
-    tokio::sync::{
-        broadcast::Receiver as BroadcastReceiver,
-        mpsc::{channel, Receiver, Sender},
-        Mutex,
+    tokio::{
+        sync::{
+            broadcast::Receiver as BroadcastReceiver,
+            mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedSender},
+            Mutex,
+        },
+        task::JoinHandle,
+        time::{sleep, timeout},
     },
+    toml::Spanned,
 };
 
 use crate::{
-    config::{Config, ConfigEvent, Mapping},
-    constants::SCRIPT_FILE_PATHS,
-    device::{Action, Event},
-    errors::{LoadScriptError, ScriptNotFound},
-    helper::parse_path,
-    EnigoCommand,
+    config::{Config, ConfigEvent, MappedKeys, Mapping, MappingAction, Mode, Permissions, Repeat},
+    constants::{SCRIPT_FILE_PATHS, WATCH_DEBOUNCE},
+    device::{Action, DeviceCommand, DeviceStatus, Devices, Event, Led, LedState},
+    errors::{ConfigLoadFailed, InvalidMappingAction, LoadScriptError, ScriptNotFound},
+    helper::{find_state_dir, is_file_change, parse_path},
+    EnigoCommand, KeyChord,
 };
 
+use {
+    context::Context,
+    coroutine::{define_coroutines, Coroutines},
+    cron::Cron,
+    environment::{create_api, create_env, lua_libraries, Capability},
+    handler::Handlers,
+    keymap::{KeyMap, KeyMaps},
+    module::{define_require, Modules},
+    process::{define_process, run_detached},
+    sequence::{Held, Outcome, Sequencer, Step},
+    status::{Status, LOADING},
+    store::{define_store, script_namespace, Store},
+    subscription::{define_on, Subscriptions, Topic},
+    timer::{define_timers, Timers},
+    toggle::Toggles,
+};
+
+pub(crate) use limits::Limiter;
+pub use status::print_status;
+
 pub struct Script {
     lua: Lua,
-    script_map: HashMap<u32, String>,
-    watcher: RecommendedWatcher,
+    api: RegistryKey,
+    envs: HashMap<PathBuf, RegistryKey>,
+    handlers: Handlers,
+    permissions: HashMap<PathBuf, Permissions>,
+    keymaps: KeyMaps,
+    held_mappings: HashMap<u32, ScriptMapping>,
+    sequence_map: Vec<ScriptTable>,
+    sequencer: Sequencer,
+    schedule_map: Vec<(Cron, ScriptTable)>,
+    toggles: Arc<StdMutex<Toggles>>,
+    store: Arc<StdMutex<Store>>,
+    modules: Arc<StdMutex<Modules>>,
+    subscriptions: Arc<StdMutex<Subscriptions>>,
+    timers: Arc<StdMutex<Timers>>,
+    status: Arc<StdMutex<Status>>,
+    coroutines: Coroutines,
+    limiter: Limiter,
+    device: Devices,
+    pressed: HashMap<u32, Instant>,
+    enigo_tx: Sender<EnigoCommand>,
+    /// LED changes, forwarded to the device in the order they're made.
+    led_tx: UnboundedSender<DeviceCommand>,
+    /// The script itself, for tasks that call back into it later.
+    handle: Weak<Mutex<Script>>,
+    _watcher: RecommendedWatcher,
+}
+
+/// A config being loaded, kept apart from the running one until it has
+/// loaded without errors.
+#[derive(Default)]
+struct Pending {
+    permissions: HashMap<PathBuf, Permissions>,
+    scripts: HashMap<PathBuf, Staged>,
+    handlers: Handlers,
+    /// Timers of inline snippets being replaced, cancelled once they are.
+    inline_timers: Vec<u64>,
+    base: KeyMap,
+    layers: HashMap<String, KeyMap>,
+    toggle_leds: Vec<u32>,
+    latched_layers: Vec<String>,
+    sequences: Vec<Vec<u32>>,
+    sequence_map: Vec<ScriptTable>,
+    schedule_map: Vec<(Cron, ScriptTable)>,
+    registered: HashSet<PathBuf>,
+    errors: Vec<Error>,
+}
+
+/// A script run in a new environment which hasn't replaced the running
+/// version of it yet.
+struct Staged {
+    env: RegistryKey,
+    registered: Subscriptions,
+    /// Timers the running version started, cancelled once it's replaced.
+    replaced_timers: Vec<u64>,
+    /// Timers the new version started while loading, cancelled if it's
+    /// discarded.
+    started_timers: Vec<u64>,
+    /// What the running version required, put back if the new one is
+    /// discarded.
+    required: Vec<PathBuf>,
+}
+
+impl Pending {
+    fn has_method(&self, table: &ScriptTable, method: &str) -> bool {
+        self.handlers.contains(table, method)
+            || self
+                .scripts
+                .get(&table.path)
+                .is_some_and(|staged| staged.registered.mapped(&table.path, method).is_some())
+    }
+
+    /// Warns about any of `methods` the table doesn't define, so a mapping to
+    /// a missing handler shows up when it's loaded rather than on key press.
+    fn check_handlers(&self, table: &ScriptTable, methods: &[&str]) {
+        for method in methods {
+            if !self.has_method(table, method) {
+                warn!(
+                    "Script {} doesn't define handler {}.{}",
+                    table.path.display(),
+                    table.name,
+                    method
+                );
+            }
+        }
+    }
+}
+
+/// The Lua table a script file defines, named after the file. It lives in the
+/// environment the file at `path` was loaded into.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ScriptTable {
+    path: PathBuf,
+    name: String,
+}
+
+/// What a key does when pressed: call a script's handlers or perform one of
+/// the built-in actions.
+#[derive(Clone, Debug)]
+enum Target {
+    Script(ScriptTable),
+    Action(BuiltIn),
+}
+
+#[derive(Clone, Debug)]
+enum BuiltIn {
+    Text(String),
+    Combo(Vec<KeyChord>),
+    Run(Vec<String>),
+    Layer(String),
+}
+
+#[derive(Clone, Debug)]
+struct ScriptMapping {
+    target: Target,
+    repeat: Option<Repeat>,
+    mode: Mode,
+    led: bool,
+    params: Option<toml::Table>,
 }
 
 impl Script {
     pub async fn new(
         config: Arc<Mutex<Config>>,
         enigo_tx: Sender<EnigoCommand>,
+        device_tx: Sender<DeviceCommand>,
     ) -> Result<Arc<Mutex<Self>>> {
+        Self::with_state_dir(config, enigo_tx, device_tx, find_state_dir()).await
+    }
+
+    /// Like `new`, keeping toggles, the store and the status in `state_dir`.
+    async fn with_state_dir(
+        config: Arc<Mutex<Config>>,
+        enigo_tx: Sender<EnigoCommand>,
+        device_tx: Sender<DeviceCommand>,
+        state_dir: Option<PathBuf>,
+    ) -> Result<Arc<Mutex<Self>>> {
+        let state_dir = state_dir.as_deref();
         let (tx, rx) = channel::<Result<NotifyEvent, NotifyError>>(32);
 
-        let watcher = notify::recommended_watcher(
+        let mut watcher = notify::recommended_watcher(
             move |res: Result<NotifyEvent, NotifyError>| {
                 if tx.blocking_send(res).is_err() {}
             },
         )?;
 
-        let lua = unsafe { Lua::unsafe_new() };
+        // Directories rather than files are watched, since editors that save
+        // by writing a new file and renaming it over the old one would leave
+        // a file watch on the replaced file.
+        for dir in SCRIPT_FILE_PATHS.iter().map(|path| parse_path(path)) {
+            if dir.is_dir() {
+                watcher.watch(&dir, RecursiveMode::Recursive)?;
+            }
+        }
+
+        let lua = Lua::new_with(lua_libraries(), LuaOptions::new())?;
+        let limiter = Limiter::default();
+        limiter.install(&lua)?;
+        let api = create_api(&lua)?;
+        let api = lua.create_registry_value(api)?;
+        let device = config.lock().await.device;
+        let led_tx = forward_in_order(device_tx.clone());
+        let status = Arc::new(StdMutex::new(Status::new(state_dir)));
 
-        let script = Arc::new(Mutex::new(Self {
-            lua,
-            script_map: HashMap::new(),
-            watcher,
-        }));
+        let script_arc = Arc::new_cyclic(|script| {
+            Mutex::new(Self {
+                lua,
+                api,
+                envs: HashMap::new(),
+                handlers: Handlers::default(),
+                permissions: HashMap::new(),
+                keymaps: KeyMaps::default(),
+                held_mappings: HashMap::new(),
+                sequence_map: vec![],
+                sequencer: Sequencer::default(),
+                schedule_map: vec![],
+                toggles: Arc::new(StdMutex::new(Toggles::load(state_dir))),
+                store: Arc::new(StdMutex::new(Store::load(state_dir))),
+                modules: Arc::new(StdMutex::new(Modules::default())),
+                subscriptions: Arc::new(StdMutex::new(Subscriptions::default())),
+                timers: Arc::new(StdMutex::new(Timers::default())),
+                coroutines: Coroutines::new(
+                    script.clone(),
+                    limiter.clone(),
+                    status.clone(),
+                    device_tx.clone(),
+                ),
+                status,
+                limiter,
+                device,
+                pressed: HashMap::new(),
+                enigo_tx: enigo_tx.clone(),
+                led_tx: led_tx.clone(),
+                handle: script.clone(),
+                _watcher: watcher,
+            })
+        });
         {
-            let mut script = script.lock().await;
+            let mut script = script_arc.lock().await;
             {
-                let globals = script.lua.globals();
+                let api: Table = script.lua.registry_value(&script.api)?;
+                let common: Table = api.get("common")?;
+                let keyboard: Table = api.get(Capability::Keyboard.name())?;
+                let mouse: Table = api.get(Capability::Mouse.name())?;
+                let leds: Table = api.get(Capability::Leds.name())?;
+                let exec: Table = api.get(Capability::Exec.name())?;
 
-                define_keys(enigo_tx.clone(), &script.lua, &globals)?;
-                define_raw_keys(enigo_tx, &script.lua, &globals)?;
+                define_coroutines(&script.lua, &common)?;
+                script.limiter.define(&script.lua, &common)?;
+                define_keys(enigo_tx.clone(), &script.lua, &keyboard)?;
+                define_raw_keys(enigo_tx.clone(), &script.lua, &keyboard)?;
+                define_mouse(enigo_tx, &script.lua, &mouse)?;
+                define_device(led_tx, device, &script.lua, &leds)?;
+                define_toggles(script.toggles.clone(), &script.lua, &common)?;
+                define_process(Arc::downgrade(&script_arc), &script.lua, &exec)?;
 
                 #[cfg(target_os = "macos")]
                 {
@@ -79,92 +304,946 @@ impl Script {
                                 }
                                 Ok(())
                             })?;
-                    globals.set("hid_post_aux_key", hid_post_aux_key)?;
+                    keyboard.set("hid_post_aux_key", hid_post_aux_key)?;
                 }
             }
 
             script.load_mapping(&*config.lock().await)?;
         }
 
-        tokio::spawn(script_watcher(script.clone(), rx));
+        tokio::spawn(script_watcher(script_arc.clone(), rx));
+        tokio::spawn(schedule_loop(script_arc.clone()));
 
-        Ok(script)
+        Ok(script_arc)
     }
 
+    /// Loads everything the config maps. The new mappings, sequences,
+    /// schedules and the scripts behind them are built completely and only
+    /// replace the running ones if all of them load; otherwise every problem
+    /// is logged and the previous set stays active.
     pub fn load_mapping(&mut self, conf: &Config) -> Result<()> {
-        for mapping in &conf.mappings {
+        let permissions = conf
+            .permissions
+            .iter()
+            .filter_map(|(script, permissions)| {
+                let path = match conf.is_lua() && conf.path.ends_with(script) {
+                    true => conf.path.canonicalize().ok()?,
+                    false => find_script(script)?.canonicalize().ok()?,
+                };
+                Some((path, permissions.clone()))
+            })
+            .collect();
+
+        // The new scripts' top-level code runs under the limits they'll be
+        // held to once they're swapped in.
+        let previous_limits = self.limiter.limits();
+        self.limiter.set_limits(&self.lua, conf.limits)?;
+
+        let mut pending = Pending {
+            permissions,
+            ..Pending::default()
+        };
+        self.load_pending(conf, &mut pending);
+
+        if !pending.errors.is_empty() {
+            for err in &pending.errors {
+                error!("Error loading config: {:#}", err);
+            }
+            let failed = pending.errors.len();
+            self.discard(pending);
+            self.limiter.set_limits(&self.lua, previous_limits)?;
+            return Err(Error::new(ConfigLoadFailed(failed)));
+        }
+
+        self.commit(conf, pending)
+    }
+
+    fn load_pending(&mut self, conf: &Config, pending: &mut Pending) {
+        pending.base = self.load_key_map("mappings", &conf.mappings, conf, pending);
+        for (name, layer) in &conf.layers {
+            trace!("Loading layer: {}", name);
+            let section = format!("layers.{}.mappings", name);
+            let key_map = self.load_key_map(&section, &layer.mappings, conf, pending);
+            pending.layers.insert(name.clone(), key_map);
+        }
+
+        for script in &conf.scripts {
+            trace!("Loading script: {}", script);
+            let result = find_script(script)
+                .ok_or_else(|| Error::new(ScriptNotFound))
+                .and_then(|full_path| self.stage(&full_path, pending));
+            match result {
+                Ok(path) => {
+                    if !pending.scripts[&path].registered.contains(&path) {
+                        warn!("Script {} doesn't register any handlers", path.display());
+                    }
+                    pending.registered.insert(path);
+                }
+                Err(err) => pending
+                    .errors
+                    .push(err.context(format!("scripts: '{}'", script))),
+            }
+        }
+
+        // An init.lua declares handlers alongside the config. The config
+        // watcher reloads it along with the config.
+        if conf.is_lua() {
+            trace!("Loading config as script: {}", conf.path.display());
+            match self.stage(&conf.path, pending) {
+                Ok(path) => {
+                    pending.registered.insert(path);
+                }
+                Err(err) => pending.errors.push(err.context("init.lua handlers")),
+            }
+        }
+
+        for (index, sequence) in conf.sequences.iter().enumerate() {
+            trace!("Loading sequence: {:?}", sequence);
+            match self.load_named_table(&sequence.script, &["Press"], pending) {
+                Ok(table) => {
+                    pending.sequences.push(sequence.keys.clone());
+                    pending.sequence_map.push(table);
+                }
+                Err(err) => pending
+                    .errors
+                    .push(err.context(format!("sequences #{}", index + 1))),
+            }
+        }
+
+        for (index, schedule) in conf.schedules.iter().enumerate() {
+            trace!("Loading schedule: {:?}", schedule);
+            let result = schedule.cron.parse::<Cron>().and_then(|cron| {
+                let table = self.load_named_table(&schedule.script, &["Scheduled"], pending)?;
+                Ok((cron, table))
+            });
+            match result {
+                Ok(entry) => pending.schedule_map.push(entry),
+                Err(err) => pending
+                    .errors
+                    .push(err.context(format!("schedules #{}", index + 1))),
+            }
+        }
+    }
+
+    /// Swaps in a fully loaded config.
+    fn commit(&mut self, conf: &Config, pending: Pending) -> Result<()> {
+        self.coroutines.set_error_led(conf.error_led);
+        self.device = conf.device;
+        self.permissions = pending.permissions;
+
+        for (path, staged) in pending.scripts {
+            self.install(path, staged)?;
+        }
+        // Every table the config uses was resolved against its new
+        // environment, so the handlers of tables it no longer uses go too.
+        self.handlers = pending.handlers;
+        self.timers.lock().unwrap().cancel(&pending.inline_timers);
+
+        // A release after the reload goes to the new mapping rather than one
+        // the config no longer has.
+        self.held_mappings.clear();
+        self.keymaps.replace(pending.base, pending.layers);
+        for name in pending.latched_layers {
+            self.keymaps.set_active(&name, true);
+        }
+        for key in pending.toggle_leds {
+            let latched = self.toggles.lock().unwrap().is_latched(key);
+            self.set_toggle_led(key, latched);
+        }
+
+        self.sequencer = Sequencer::new(
+            pending.sequences,
+            Duration::from_millis(conf.sequence_timeout),
+            conf.sequence_cancel,
+        );
+        self.sequence_map = pending.sequence_map;
+        self.schedule_map = pending.schedule_map;
+
+        // Handlers registered by scripts the config no longer uses would
+        // otherwise keep firing, and their environments would have them
+        // reloaded, registering them again, when their files change.
+        let in_use: HashSet<&PathBuf> = pending
+            .registered
+            .iter()
+            .chain(self.keymaps.script_paths())
+            .chain(self.sequence_map.iter().map(|table| &table.path))
+            .chain(self.schedule_map.iter().map(|(_, table)| &table.path))
+            .collect();
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .retain(&self.lua, |path| in_use.contains(&PathBuf::from(path)))?;
+        self.timers
+            .lock()
+            .unwrap()
+            .retain(|path| in_use.contains(&PathBuf::from(path)));
+        let dropped: Vec<PathBuf> = self
+            .envs
+            .keys()
+            .filter(|path| !in_use.contains(path))
+            .cloned()
+            .collect();
+        for path in dropped {
+            if let Some(env) = self.envs.remove(&path) {
+                self.lua.remove_registry_value(env)?;
+            }
+            self.modules.lock().unwrap().forget(&path);
+        }
+        self.lua.expire_registry_values();
+
+        Ok(())
+    }
+
+    /// Throws away the scripts staged for a config that failed to load,
+    /// leaving the running versions as they were.
+    fn discard(&mut self, mut pending: Pending) {
+        for (path, staged) in pending.scripts.drain() {
+            self.timers.lock().unwrap().cancel(&staged.started_timers);
+            self.modules.lock().unwrap().restore(&path, staged.required);
+        }
+        drop(pending);
+        self.lua.expire_registry_values();
+    }
+
+    /// Loads the top level mappings or a layer's, adding any problems to
+    /// `pending` rather than stopping at the first.
+    fn load_key_map(
+        &mut self,
+        section: &str,
+        mappings: &[Mapping],
+        conf: &Config,
+        pending: &mut Pending,
+    ) -> KeyMap {
+        let mut key_map = KeyMap::default();
+
+        for (index, mapping) in mappings.iter().enumerate() {
             trace!("Loading mapping: {:?}", mapping);
-            if let Some(full_path) = find_script(&mapping.script) {
-                match self.load_script_mapping(&full_path, mapping) {
-                    Ok(()) => continue,
-                    Err(err) => {
-                        if err.downcast_ref::<ScriptNotFound>().is_some() {
-                            continue;
-                        } else {
-                            error!("Error loading mapping: {:?}, {}", mapping, err);
-                            return Err(err);
-                        }
+            let result = mapping
+                .mapped_keys()
+                .and_then(|keys| Ok((keys, self.load_script_mapping(mapping, conf, pending)?)));
+            let (keys, script_mapping) = match result {
+                Ok((keys, Some(script_mapping))) => (keys, script_mapping),
+                Ok((_, None)) => continue,
+                Err(err) => {
+                    let err = err.context(format!("{} #{}", section, index + 1));
+                    pending.errors.push(err);
+                    continue;
+                }
+            };
+
+            if let (MappedKeys::Keys(keys), Mode::Toggle) = (&keys, mapping.mode) {
+                for &key in keys {
+                    if mapping.led {
+                        pending.toggle_leds.push(key);
+                    }
+                    let latched = self.toggles.lock().unwrap().is_latched(key);
+                    if let (true, Target::Action(BuiltIn::Layer(name))) =
+                        (latched, &script_mapping.target)
+                    {
+                        pending.latched_layers.push(name.clone());
                     }
+                }
+            }
+
+            for key in key_map.insert(keys, script_mapping) {
+                warn!("Key {} is mapped more than once in {}", key, section);
+            }
+        }
+
+        key_map
+    }
+
+    /// Loads the table named after `script` and checks it defines `methods`.
+    fn load_named_table(
+        &mut self,
+        script: &str,
+        methods: &[&str],
+        pending: &mut Pending,
+    ) -> Result<ScriptTable> {
+        let full_path = find_script(script)
+            .ok_or_else(|| Error::new(ScriptNotFound).context(format!("script '{}'", script)))?;
+        let table = self.load_table(&full_path, script, pending)?;
+        pending.check_handlers(&table, methods);
+        Ok(table)
+    }
+
+    /// Resolves a mapping's action, loading its script if it has one. Returns
+    /// `None` if the script disappeared while it was being loaded.
+    fn load_script_mapping(
+        &mut self,
+        mapping: &Mapping,
+        conf: &Config,
+        pending: &mut Pending,
+    ) -> Result<Option<ScriptMapping>> {
+        let invalid = |reason: String| Error::new(InvalidMappingAction(reason));
+
+        let target = match mapping.action()? {
+            MappingAction::Script(script) => {
+                let full_path = find_script(&script).ok_or_else(|| {
+                    Error::new(ScriptNotFound).context(format!("script '{}'", script))
+                })?;
+                let table = match self.load_table(&full_path, &script, pending) {
+                    Ok(table) => table,
+                    Err(err) if err.downcast_ref::<ScriptNotFound>().is_some() => return Ok(None),
+                    Err(err) => return Err(err),
                 };
-            } else {
-                error!("Script not found: {:?}", mapping);
-                return Err(Error::new(ScriptNotFound));
+                match mapping.mode {
+                    Mode::Momentary => pending.check_handlers(&table, &["Press"]),
+                    Mode::Toggle => pending.check_handlers(&table, &["On", "Off"]),
+                }
+                Target::Script(table)
+            }
+            MappingAction::Text(_) | MappingAction::Combo(_) | MappingAction::Run(_)
+                if mapping.mode == Mode::Toggle =>
+            {
+                return Err(invalid(String::from("built-in actions can't toggle")));
             }
+            MappingAction::Text(text) => Target::Action(BuiltIn::Text(text)),
+            MappingAction::Combo(combo) => {
+                let chords = helper::parse_combo(&combo)
+                    .map_err(|err| invalid(format!("key combo {:?}: {}", combo, err)))?;
+                Target::Action(BuiltIn::Combo(chords))
+            }
+            MappingAction::Run(command) => Target::Action(BuiltIn::Run(command)),
+            MappingAction::Layer(name) => {
+                if !conf.layers.contains_key(&name) {
+                    return Err(invalid(format!("no layer named '{}'", name)));
+                }
+                Target::Action(BuiltIn::Layer(name))
+            }
+            MappingAction::Inline { press, release } => {
+                if mapping.mode == Mode::Toggle {
+                    return Err(invalid(String::from("inline snippets can't toggle")));
+                }
+                let snippets = [("Press", press), ("Release", release)];
+                Target::Script(self.load_inline(conf, &snippets, pending)?)
+            }
+        };
+
+        Ok(Some(ScriptMapping {
+            target,
+            repeat: mapping.repeat,
+            mode: mapping.mode,
+            led: mapping.led,
+            params: mapping.params.clone(),
+        }))
+    }
+
+    /// Compiles a mapping's inline snippets into the handlers of an anonymous
+    /// table, identified by the config line the first snippet starts on. Each
+    /// snippet is padded so Lua reports errors against lines of the config.
+    /// Snippets have no entry in the `permissions` table, so they always get
+    /// the default permissions.
+    fn load_inline(
+        &mut self,
+        conf: &Config,
+        snippets: &[(&str, Option<Spanned<String>>)],
+        pending: &mut Pending,
+    ) -> Result<ScriptTable> {
+        let line = snippets
+            .iter()
+            .find_map(|(_, snippet)| snippet.as_ref())
+            .map_or(1, |snippet| conf.snippet_line(snippet));
+        let name = format!("{}:{}", conf.path.display(), line);
+
+        let api: Table = self.lua.registry_value(&self.api)?;
+        let env = create_env(&self.lua, &api, &Permissions::default(), &name)?;
+        let path = PathBuf::from(&name);
+        define_timers(
+            self.handle.clone(),
+            self.timers.clone(),
+            &self.lua,
+            &env,
+            &path,
+        )?;
+        let methods = self.lua.create_table()?;
+
+        for (method, snippet) in snippets {
+            if let Some(snippet) = snippet {
+                let padding = "\n".repeat(conf.snippet_line(snippet) - 1);
+                let source = format!("local event = ...; {}{}", padding, snippet.get_ref());
+                let func = self
+                    .lua
+                    .load(&source)
+                    .set_name(format!("@{}", conf.path.display()))?
+                    .set_environment(env.clone())?
+                    .into_function()?;
+                methods.set(*method, func)?;
+            }
+        }
+
+        let table = ScriptTable {
+            path,
+            name: String::from("Inline"),
+        };
+        env.set(table.name.as_str(), methods)?;
+        pending.handlers.resolve(&self.lua, &env, &table)?;
+
+        let timers = self.timers.lock().unwrap().started_by(&table.path);
+        pending.inline_timers.extend(timers);
+
+        Ok(table)
+    }
+
+    fn load_table(
+        &mut self,
+        path: &Path,
+        script_name: &str,
+        pending: &mut Pending,
+    ) -> Result<ScriptTable> {
+        let path = self.stage(path, pending)?;
+        let name = Path::new(script_name).file_stem().unwrap();
+        let table = ScriptTable {
+            path,
+            name: String::from(name.to_str().unwrap()),
+        };
+
+        let staged = &pending.scripts[&table.path];
+        let env: Table = self.lua.registry_value(&staged.env)?;
+        let registered = staged.registered.contains(&table.path);
+        if !pending.handlers.resolve(&self.lua, &env, &table)? && !registered {
+            warn!(
+                "Script {} doesn't define a table named {}",
+                table.path.display(),
+                table.name
+            );
+        }
+
+        Ok(table)
+    }
+
+    /// Executes the script at `path` in a new environment, replacing any
+    /// environment it was loaded into before. Returns the canonical path the
+    /// environment is stored under.
+    pub fn load_script(&mut self, path: &Path) -> Result<PathBuf> {
+        let permissions = fs::canonicalize(path)
+            .ok()
+            .and_then(|path| self.permissions.get(&path).cloned())
+            .unwrap_or_default();
+        let (path, staged) = self.stage_script(path, &permissions)?;
+        self.install(path.clone(), staged)?;
+
+        let env: Table = self.lua.registry_value(&self.envs[&path])?;
+        self.handlers.refresh(&self.lua, &env, &path)?;
+        self.lua.expire_registry_values();
+
+        Ok(path)
+    }
+
+    /// Stages the script at `path` for the pending config. A script the
+    /// config refers to more than once is only run once.
+    fn stage(&mut self, path: &Path, pending: &mut Pending) -> Result<PathBuf> {
+        if let Ok(canonical) = fs::canonicalize(path) {
+            if pending.scripts.contains_key(&canonical) {
+                return Ok(canonical);
+            }
+        }
+
+        let permissions = fs::canonicalize(path)
+            .ok()
+            .and_then(|path| pending.permissions.get(&path).cloned())
+            .unwrap_or_default();
+        let (path, staged) = self.stage_script(path, &permissions)?;
+        pending.scripts.insert(path.clone(), staged);
+
+        Ok(path)
+    }
+
+    /// Executes the script at `path` in a new environment without replacing
+    /// the running version. A version that fails leaves nothing behind.
+    fn stage_script(
+        &mut self,
+        path: &Path,
+        permissions: &Permissions,
+    ) -> Result<(PathBuf, Staged)> {
+        if !path.exists() {
+            return Err(Error::new(ScriptNotFound));
+        }
+        let script = fs::read_to_string(path).map_err(|_| Error::new(LoadScriptError))?;
+
+        trace!("Loading script: {}", path.display());
+        let path = fs::canonicalize(path)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        let api: Table = self.lua.registry_value(&self.api)?;
+        let env = create_env(&self.lua, &api, permissions, &name)?;
+        let namespace = script_namespace(&path);
+        define_store(self.store.clone(), &self.lua, &env, &namespace)?;
+        define_require(self.modules.clone(), &self.lua, &env, &path)?;
+        define_on(self.subscriptions.clone(), &self.lua, &env, &path)?;
+        define_timers(
+            self.handle.clone(),
+            self.timers.clone(),
+            &self.lua,
+            &env,
+            &path,
+        )?;
+
+        let required = self.modules.lock().unwrap().forget(&path);
+        let replaced_timers = self.timers.lock().unwrap().started_by(&path);
+        self.subscriptions.lock().unwrap().begin(&path);
+        self.limiter.start();
+        let result = self
+            .lua
+            .load(&script)
+            .set_name(format!("@{}", path.display()))
+            .and_then(|chunk| chunk.set_environment(env.clone()))
+            .and_then(|chunk| chunk.exec());
+        self.limiter.stop();
+        let registered = self.subscriptions.lock().unwrap().finish();
+        let started_timers: Vec<u64> = self
+            .timers
+            .lock()
+            .unwrap()
+            .started_by(&path)
+            .into_iter()
+            .filter(|timer| !replaced_timers.contains(timer))
+            .collect();
+
+        if let Err(err) = result {
+            self.modules.lock().unwrap().restore(&path, required);
+            self.timers.lock().unwrap().cancel(&started_timers);
+            drop(registered);
+            self.lua.expire_registry_values();
+
+            let script = path.display().to_string();
+            let message = err.to_string();
+            self.status
+                .lock()
+                .unwrap()
+                .failed(&script, LOADING, &message);
+            return Err(err.into());
+        }
+
+        let staged = Staged {
+            env: self.lua.create_registry_value(env)?,
+            registered,
+            replaced_timers,
+            started_timers,
+            required,
+        };
+        Ok((path, staged))
+    }
+
+    /// Replaces the running version of the script at `path` with a staged
+    /// one.
+    fn install(&mut self, path: PathBuf, staged: Staged) -> Result<()> {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .merge(&self.lua, staged.registered)?;
+        if let Some(old_env) = self.envs.insert(path.clone(), staged.env) {
+            self.lua.remove_registry_value(old_env)?;
         }
+        // Timers the replaced version started would call into it.
+        self.timers.lock().unwrap().cancel(&staged.replaced_timers);
+        self.status
+            .lock()
+            .unwrap()
+            .loaded(&path.display().to_string());
 
         Ok(())
     }
 
-    pub fn load_script_mapping(&mut self, path: &Path, mapping: &Mapping) -> Result<()> {
-        if path.exists() {
-            if let Ok(script) = fs::read_to_string(path) {
-                trace!("Loading script: {}", path.display());
-                self.lua.load(&script).exec()?;
-                let name = Path::new(&mapping.script).file_stem().unwrap();
-                self.script_map
-                    .insert(mapping.key, String::from(name.to_str().unwrap()));
-                self.watcher.watch(path, RecursiveMode::NonRecursive)?;
-                Ok(())
-            } else {
-                Err(Error::new(LoadScriptError))
+    /// Reloads the file at `path` after it changed: the script itself if it's
+    /// loaded, and every script that required it as a module.
+    fn reload(&mut self, path: &Path) {
+        let path = match fs::canonicalize(path) {
+            Ok(path) => path,
+            Err(e) => {
+                error!("Unable to load script: {} -- {}", path.display(), e);
+                return;
+            }
+        };
+
+        let mut scripts = self.modules.lock().unwrap().dependents(&path);
+        if self.envs.contains_key(&path) {
+            scripts.push(path);
+        }
+
+        for script in scripts {
+            info!("Updating script: {}", script.display());
+            if let Err(e) = self.load_script(&script) {
+                error!("Unable to load script: {} -- {}", script.display(), e);
             }
-        } else {
-            Err(Error::new(ScriptNotFound))
         }
     }
 
-    pub fn load_script(&mut self, path: &Path) -> Result<()> {
-        if path.exists() {
-            if let Ok(script) = fs::read_to_string(path) {
-                self.lua.load(&script).exec()?;
+    /// Runs `table.method` as a new coroutine, passing it the context as a
+    /// table. The context's key, if any, is what `waitForRelease` waits on.
+    /// Tables without the method are skipped; missing handlers are reported at
+    /// load time.
+    fn execute(&mut self, table: &ScriptTable, method: &str, context: Context) {
+        let name = format!("{}.{}", table.name, method);
 
-                Ok(())
-            } else {
-                Err(Error::new(LoadScriptError))
+        let subscriptions = self.subscriptions.lock().unwrap();
+        let handler = find_handler(&self.lua, &self.handlers, &subscriptions, table, method)
+            .and_then(|func| Ok((func, context.to_table(&self.lua)?)));
+        drop(subscriptions);
+
+        match handler {
+            Ok((Some(func), args)) => {
+                self.coroutines
+                    .spawn(&self.lua, &name, func, context.key, args)
             }
-        } else {
-            Err(Error::new(ScriptNotFound))
+            Ok((None, _)) => trace!("No handler defined: {}", name),
+            Err(err) => error!("Failed to execute script ({}): {}", name, err),
         }
     }
+
+    /// Runs every handler registered with `on` for `topic`.
+    fn notify(&mut self, topic: &Topic, context: &Context) {
+        let handlers: Vec<(String, mlua::Result<Function>)> = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .matching(topic)
+            .into_iter()
+            .map(|(script, key)| {
+                let name = script.file_name().unwrap_or_default().to_string_lossy();
+                let name = format!("{} on {:?}", name, topic);
+                (name, self.lua.registry_value(key))
+            })
+            .collect();
+
+        for (name, func) in handlers {
+            match func.and_then(|func| Ok((func, context.to_table(&self.lua)?))) {
+                Ok((func, args)) => {
+                    self.coroutines
+                        .spawn(&self.lua, &name, func, context.key, args)
+                }
+                Err(err) => error!("Failed to execute script ({}): {}", name, err),
+            }
+        }
+    }
+
+    fn set_toggle_led(&self, key: u32, latched: bool) {
+        let state = match latched {
+            true => LedState::On,
+            false => LedState::Off,
+        };
+        let command = DeviceCommand::Backlight {
+            key,
+            led: Led::Blue,
+            state,
+        };
+
+        if let Err(e) = self.led_tx.send(command) {
+            error!("Unable to send value into device channel: {}", e);
+        }
+    }
+
+    /// Runs the handlers for a device event: sequences, then the key's
+    /// toggle or momentary mapping. Returns the repeat settings if the event
+    /// should start auto-repeat for the key.
+    pub fn dispatch(&mut self, event: &Event) -> Option<Repeat> {
+        self.coroutines.key_event(&self.lua, event);
+
+        let pressed = match event.action {
+            Action::Press => {
+                let now = Instant::now();
+                self.pressed.insert(event.key, now);
+                Some(now)
+            }
+            Action::Release => self.pressed.remove(&event.key),
+        };
+
+        let Outcome { replay, step } = self.sequencer.process(event, pressed);
+        self.replay(replay);
+
+        match step {
+            Step::Pass => self.dispatch_key(event, pressed),
+            Step::Consumed => None,
+            Step::Held(ticket) => {
+                self.expire_sequence_after(ticket);
+                None
+            }
+            Step::Matched(index) => {
+                if let Some(table) = self.sequence_map.get(index).cloned() {
+                    let context = Context::new(self.device, Some(event.key), "press", pressed);
+                    self.execute(&table, "Press", context);
+                }
+                None
+            }
+        }
+    }
+
+    /// Dispatches events a sequence held back, now that it won't complete.
+    /// They don't start auto-repeat, since their key may be up already.
+    fn replay(&mut self, replay: Vec<Held>) {
+        for held in replay {
+            trace!("Replaying key event: {:?}", held.event);
+            self.dispatch_key(&held.event, held.pressed);
+        }
+    }
+
+    /// Replays what the sequence in progress held back if it's still waiting
+    /// on `ticket` once the sequence timeout passes.
+    fn expire_sequence_after(&self, ticket: u64) {
+        let script = self.handle.clone();
+        let timeout = self.sequencer.timeout();
+        tokio::spawn(async move {
+            sleep(timeout).await;
+
+            if let Some(script) = script.upgrade() {
+                let mut script = script.lock().await;
+                let replay = script.sequencer.expire(ticket);
+                script.replay(replay);
+            }
+        });
+    }
+
+    /// Runs the handlers for a key event the sequencer let through.
+    fn dispatch_key(&mut self, event: &Event, pressed: Option<Instant>) -> Option<Repeat> {
+        let action = match event.action {
+            Action::Press => "press",
+            Action::Release => "release",
+        };
+        let mut context = Context::new(self.device, Some(event.key), action, pressed);
+
+        self.notify(&Topic::Key(event.key, action), &context);
+        self.notify(&Topic::Any, &context);
+
+        // Release goes to whatever handled the press, even if a layer has
+        // changed in between.
+        let mapping = match event.action {
+            Action::Press => {
+                let mapping = self.keymaps.get(event.key).cloned();
+                if let Some(mapping) = &mapping {
+                    self.held_mappings.insert(event.key, mapping.clone());
+                }
+                mapping
+            }
+            Action::Release => self
+                .held_mappings
+                .remove(&event.key)
+                .or_else(|| self.keymaps.get(event.key).cloned()),
+        };
+        let ScriptMapping {
+            target,
+            repeat,
+            mode,
+            led,
+            params,
+        } = mapping?;
+        context.params = params;
+
+        let table = match target {
+            Target::Script(table) => table,
+            Target::Action(BuiltIn::Layer(name)) => {
+                self.switch_layer(&name, event, mode, led);
+                return None;
+            }
+            Target::Action(action) => {
+                return match event.action {
+                    Action::Press => {
+                        self.perform(&action);
+                        repeat
+                    }
+                    Action::Release => None,
+                };
+            }
+        };
+
+        if mode == Mode::Toggle {
+            if event.action == Action::Press {
+                let latched = self.toggles.lock().unwrap().flip(event.key);
+                let method = match latched {
+                    true => "On",
+                    false => "Off",
+                };
+
+                self.execute(&table, method, context);
+
+                if led {
+                    self.set_toggle_led(event.key, latched);
+                }
+            }
+            return None;
+        }
+
+        let method = match event.action {
+            Action::Press => "Press",
+            Action::Release => "Release",
+        };
+
+        self.execute(&table, method, context);
+
+        match event.action {
+            Action::Press => repeat,
+            Action::Release => None,
+        }
+    }
+
+    /// Turns a layer on while its key is held, or on and off with each press
+    /// in toggle mode.
+    fn switch_layer(&mut self, name: &str, event: &Event, mode: Mode, led: bool) {
+        match (mode, &event.action) {
+            (Mode::Momentary, action) => {
+                self.keymaps.set_active(name, *action == Action::Press);
+            }
+            (Mode::Toggle, Action::Press) => {
+                let latched = self.toggles.lock().unwrap().flip(event.key);
+                self.keymaps.set_active(name, latched);
+                if led {
+                    self.set_toggle_led(event.key, latched);
+                }
+            }
+            (Mode::Toggle, Action::Release) => {}
+        }
+        debug!("Layer {} switched by key {}", name, event.key);
+    }
+
+    /// Performs a built-in action other than switching layers.
+    fn perform(&self, action: &BuiltIn) {
+        let command = match action {
+            BuiltIn::Text(text) => EnigoCommand::Text {
+                text: text.clone(),
+                delay: None,
+            },
+            BuiltIn::Combo(chords) => EnigoCommand::Combo(chords.clone()),
+            BuiltIn::Run(command) => return run_detached(command),
+            BuiltIn::Layer(_) => return,
+        };
+
+        let enigo_tx = self.enigo_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = enigo_tx.send(command).await {
+                error!("Unable to send value into Enigo channel: {}", e);
+            }
+        });
+    }
+
+    fn has_method(&self, table: &ScriptTable, method: &str) -> bool {
+        self.handlers.contains(table, method)
+            || self
+                .subscriptions
+                .lock()
+                .unwrap()
+                .mapped(&table.path, method)
+                .is_some()
+    }
 }
 
 pub async fn script_loop(script: Arc<Mutex<Script>>, mut rx: Receiver<Event>) {
+    let mut repeats: HashMap<u32, JoinHandle<()>> = HashMap::new();
+
     loop {
         let event = rx.recv().await.unwrap();
 
-        let script = script.lock().await;
-        if let Some(table_name) = script.script_map.get(&event.key) {
-            let method = match event.action {
-                Action::Press => format!("{}.Press", table_name),
-                Action::Release => format!("{}.Release", table_name),
+        // Any running repeat for this key stops on the next event for it, so
+        // the handle is aborted while the lock is held and no further repeat
+        // can slip in after the Release handler.
+        let mut guard = script.lock().await;
+        if let Some(handle) = repeats.remove(&event.key) {
+            handle.abort();
+        }
+
+        if let Some(repeat) = guard.dispatch(&event) {
+            repeats.insert(
+                event.key,
+                tokio::spawn(repeat_loop(script.clone(), event.key, repeat)),
+            );
+        }
+    }
+}
+
+async fn repeat_loop(script: Arc<Mutex<Script>>, key: u32, repeat: Repeat) {
+    let mut interval = repeat.interval as f64;
+
+    sleep(Duration::from_millis(repeat.delay)).await;
+
+    loop {
+        {
+            let mut script = script.lock().await;
+            let (target, params) = match script.held_mappings.get(&key) {
+                Some(mapping) => (mapping.target.clone(), mapping.params.clone()),
+                None => return,
             };
 
-            trace!("Executing script: {}", method);
+            match target {
+                Target::Script(table) => {
+                    let pressed = script.pressed.get(&key).copied();
+                    let mut context = Context::new(script.device, Some(key), "repeat", pressed);
+                    context.params = params;
 
-            if let Err(err) = script.lua.load(&format!("{}()", method)).exec() {
-                error!("Failed to execute script ({}): {}", method, err);
+                    if script.has_method(&table, "Repeat") {
+                        script.execute(&table, "Repeat", context);
+                    } else {
+                        script.execute(&table, "Press", context);
+                    }
+                }
+                Target::Action(action) => script.perform(&action),
             }
         }
+
+        sleep(Duration::from_millis(interval as u64)).await;
+        interval = (interval * repeat.acceleration).max(repeat.min_interval as f64);
+    }
+}
+
+/// The table's own handler, or else one its script registered with `on`.
+fn find_handler<'lua>(
+    lua: &'lua Lua,
+    handlers: &Handlers,
+    subscriptions: &Subscriptions,
+    table: &ScriptTable,
+    method: &str,
+) -> mlua::Result<Option<Function<'lua>>> {
+    if let Some(func) = handlers.get(lua, table, method)? {
+        return Ok(Some(func));
+    }
+
+    match subscriptions.mapped(&table.path, method) {
+        Some(key) => lua.registry_value(key).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Calls the handlers registered for the device connecting and
+/// disconnecting.
+pub async fn status_loop(script: Arc<Mutex<Script>>, mut rx: Receiver<DeviceStatus>) {
+    while let Some(status) = rx.recv().await {
+        let (topic, action, joystick) = match status {
+            DeviceStatus::Connected => (Topic::Connect, "connect", None),
+            DeviceStatus::Disconnected => (Topic::Disconnect, "disconnect", None),
+            DeviceStatus::Joystick(joystick) => (Topic::Joystick, "joystick", Some(joystick)),
+        };
+
+        let mut script = script.lock().await;
+        let mut context = Context::new(script.device, None, action, None);
+        context.joystick = joystick;
+        script.notify(&topic, &context);
+    }
+}
+
+/// Wakes on every minute boundary and calls `Scheduled` on each script whose
+/// schedule matches that minute.
+async fn schedule_loop(script: Arc<Mutex<Script>>) {
+    loop {
+        let now = Local::now();
+        let next_minute = now
+            .with_second(0)
+            .and_then(|time| time.with_nanosecond(0))
+            .unwrap_or(now)
+            + ChronoDuration::minutes(1);
+
+        if let Ok(duration) = (next_minute - now).to_std() {
+            sleep(duration).await;
+        }
+
+        let mut script = script.lock().await;
+        let scheduled: Vec<ScriptTable> = script
+            .schedule_map
+            .iter()
+            .filter(|(cron, _)| cron.matches(&next_minute))
+            .map(|(_, table)| table.clone())
+            .collect();
+
+        for table in scheduled {
+            let context = Context::new(script.device, None, "scheduled", None);
+            script.execute(&table, "Scheduled", context);
+        }
     }
 }
 
@@ -176,8 +1255,8 @@ pub async fn config_update_handler(
     loop {
         let event = rx.recv().await;
 
-        if let Ok(event) = event {
-            if event == ConfigEvent::Mapping {
+        match event {
+            Ok(ConfigEvent::Mapping) => {
                 let mut script = script.lock().await;
                 let conf = config.lock().await;
 
@@ -185,6 +1264,11 @@ pub async fn config_update_handler(
                     error!("Couldn't load scripts from Config: {}", e);
                 }
             }
+            Ok(ConfigEvent::Device) => {
+                let mut script = script.lock().await;
+                script.device = config.lock().await.device;
+            }
+            _ => {}
         }
     }
 }
@@ -202,58 +1286,86 @@ fn find_script(script: &str) -> Option<PathBuf> {
     None
 }
 
-fn define_keys(enigo_tx: Sender<EnigoCommand>, lua: &Lua, globals: &mlua::Table) -> Result<()> {
+fn define_keys(enigo_tx: Sender<EnigoCommand>, lua: &Lua, api: &mlua::Table) -> Result<()> {
     let enigo_copy = enigo_tx.clone();
     let key_click = lua.create_function(move |_lua, val: String| {
         trace!("Key click fired from Lua: {}", val);
+        let key = parse_key(&val)?;
         let enigo_copy = enigo_copy.clone();
         tokio::spawn(async move {
-            if let Err(e) = enigo_copy
-                .send(EnigoCommand::KeyClick(helper::map_str_to_key(&val)))
-                .await
-            {
+            if let Err(e) = enigo_copy.send(EnigoCommand::KeyClick(key)).await {
                 error!("Unable to send value into Enigo channel: {}", e);
             }
         });
         Ok(())
     })?;
-    globals.set("keyClick", key_click)?;
+    api.set("keyClick", key_click)?;
 
     let enigo_copy = enigo_tx.clone();
     let key_press = lua.create_function(move |_lua, val: String| {
         trace!("Key press fired from Lua: {}", val);
+        let key = parse_key(&val)?;
         let enigo_copy = enigo_copy.clone();
         tokio::spawn(async move {
-            if let Err(e) = enigo_copy
-                .send(EnigoCommand::KeyDown(helper::map_str_to_key(&val)))
-                .await
-            {
+            if let Err(e) = enigo_copy.send(EnigoCommand::KeyDown(key)).await {
                 error!("Unable to send value into Enigo channel: {}", e);
             }
         });
         Ok(())
     })?;
-    globals.set("keyDown", key_press)?;
+    api.set("keyDown", key_press)?;
 
+    let enigo_copy = enigo_tx.clone();
     let key_release = lua.create_function(move |_lua, val: String| {
         trace!("Key press release from Lua: {}", val);
+        let key = parse_key(&val)?;
+        let enigo_copy = enigo_copy.clone();
+        tokio::spawn(async move {
+            if let Err(e) = enigo_copy.send(EnigoCommand::KeyUp(key)).await {
+                error!("Unable to send value into Enigo channel: {}", e);
+            }
+        });
+        Ok(())
+    })?;
+    api.set("keyUp", key_release)?;
+
+    let enigo_copy = enigo_tx.clone();
+    let type_text = lua.create_function(move |_lua, (text, delay): (String, Option<u64>)| {
+        trace!("Type text fired from Lua: {} {:?}", text, delay);
+        let enigo_copy = enigo_copy.clone();
+        tokio::spawn(async move {
+            if let Err(e) = enigo_copy.send(EnigoCommand::Text { text, delay }).await {
+                error!("Unable to send value into Enigo channel: {}", e);
+            }
+        });
+        Ok(())
+    })?;
+    api.set("typeText", type_text)?;
+
+    let key_combo = lua.create_function(move |_lua, combo: String| {
+        trace!("Key combo fired from Lua: {}", combo);
+        let chords = helper::parse_combo(&combo).map_err(|err| {
+            mlua::Error::RuntimeError(format!("Invalid key combo {:?}: {}", combo, err))
+        })?;
         let enigo_tx = enigo_tx.clone();
         tokio::spawn(async move {
-            if let Err(e) = enigo_tx
-                .send(EnigoCommand::KeyUp(helper::map_str_to_key(&val)))
-                .await
-            {
+            if let Err(e) = enigo_tx.send(EnigoCommand::Combo(chords)).await {
                 error!("Unable to send value into Enigo channel: {}", e);
             }
         });
         Ok(())
     })?;
-    globals.set("keyUp", key_release)?;
+    api.set("keyCombo", key_combo)?;
 
     Ok(())
 }
 
-fn define_raw_keys(enigo_tx: Sender<EnigoCommand>, lua: &Lua, globals: &mlua::Table) -> Result<()> {
+fn parse_key(val: &str) -> mlua::Result<Key> {
+    helper::map_str_to_key(val)
+        .ok_or_else(|| mlua::Error::RuntimeError(format!("Unknown key: {:?}", val)))
+}
+
+fn define_raw_keys(enigo_tx: Sender<EnigoCommand>, lua: &Lua, api: &mlua::Table) -> Result<()> {
     let enigo_copy = enigo_tx.clone();
     let key_click = lua.create_function(move |_lua, val: u16| {
         trace!("Raw key click fired from Lua: {}", val);
@@ -265,7 +1377,7 @@ fn define_raw_keys(enigo_tx: Sender<EnigoCommand>, lua: &Lua, globals: &mlua::Ta
         });
         Ok(())
     })?;
-    globals.set("rawKeyClick", key_click)?;
+    api.set("rawKeyClick", key_click)?;
 
     let enigo_copy = enigo_tx.clone();
     let key_press = lua.create_function(move |_lua, val: u16| {
@@ -278,7 +1390,7 @@ fn define_raw_keys(enigo_tx: Sender<EnigoCommand>, lua: &Lua, globals: &mlua::Ta
         });
         Ok(())
     })?;
-    globals.set("rawKeyDown", key_press)?;
+    api.set("rawKeyDown", key_press)?;
 
     let key_release = lua.create_function(move |_lua, val: u16| {
         trace!("Raw key release fired from Lua: {}", val);
@@ -290,33 +1402,330 @@ fn define_raw_keys(enigo_tx: Sender<EnigoCommand>, lua: &Lua, globals: &mlua::Ta
         });
         Ok(())
     })?;
-    globals.set("rawKeyUp", key_release)?;
+    api.set("rawKeyUp", key_release)?;
+
+    Ok(())
+}
+
+fn define_mouse(enigo_tx: Sender<EnigoCommand>, lua: &Lua, api: &mlua::Table) -> Result<()> {
+    let send = move |command: EnigoCommand| {
+        let enigo_tx = enigo_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = enigo_tx.send(command).await {
+                error!("Unable to send value into Enigo channel: {}", e);
+            }
+        });
+    };
+
+    let send_copy = send.clone();
+    let mouse_move_to = lua.create_function(move |_lua, (x, y): (i32, i32)| {
+        trace!("Mouse move to fired from Lua: {} {}", x, y);
+        send_copy(EnigoCommand::MouseMoveTo(x, y));
+        Ok(())
+    })?;
+    api.set("mouseMoveTo", mouse_move_to)?;
+
+    let send_copy = send.clone();
+    let mouse_move = lua.create_function(move |_lua, (x, y): (i32, i32)| {
+        trace!("Mouse move fired from Lua: {} {}", x, y);
+        send_copy(EnigoCommand::MouseMoveRelative(x, y));
+        Ok(())
+    })?;
+    api.set("mouseMove", mouse_move)?;
+
+    let send_copy = send.clone();
+    let mouse_click = lua.create_function(move |_lua, button: Option<String>| {
+        trace!("Mouse click fired from Lua: {:?}", button);
+        send_copy(EnigoCommand::MouseClick(parse_button(button)?));
+        Ok(())
+    })?;
+    api.set("mouseClick", mouse_click)?;
+
+    let send_copy = send.clone();
+    let mouse_down = lua.create_function(move |_lua, button: Option<String>| {
+        trace!("Mouse down fired from Lua: {:?}", button);
+        send_copy(EnigoCommand::MouseDown(parse_button(button)?));
+        Ok(())
+    })?;
+    api.set("mouseDown", mouse_down)?;
+
+    let send_copy = send.clone();
+    let mouse_up = lua.create_function(move |_lua, button: Option<String>| {
+        trace!("Mouse up fired from Lua: {:?}", button);
+        send_copy(EnigoCommand::MouseUp(parse_button(button)?));
+        Ok(())
+    })?;
+    api.set("mouseUp", mouse_up)?;
+
+    let mouse_scroll = lua.create_function(move |_lua, (x, y): (i32, i32)| {
+        trace!("Mouse scroll fired from Lua: {} {}", x, y);
+        send(EnigoCommand::MouseScroll(x, y));
+        Ok(())
+    })?;
+    api.set("mouseScroll", mouse_scroll)?;
+
+    Ok(())
+}
+
+/// Parses a mouse button name, defaulting to the left button.
+fn parse_button(button: Option<String>) -> mlua::Result<MouseButton> {
+    let button = button.as_deref().unwrap_or("left");
+    helper::map_str_to_button(button)
+        .ok_or_else(|| mlua::Error::RuntimeError(format!("Unknown mouse button: {:?}", button)))
+}
+
+/// Returns a sender whose commands reach `device_tx` in the order they were
+/// sent, which a task per command wouldn't guarantee, without blocking the
+/// caller while the device channel is full.
+fn forward_in_order(device_tx: Sender<DeviceCommand>) -> UnboundedSender<DeviceCommand> {
+    let (tx, mut rx) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(command) = rx.recv().await {
+            if let Err(e) = device_tx.send(command).await {
+                error!("Unable to send value into device channel: {}", e);
+            }
+        }
+    });
+    tx
+}
+
+fn define_device(
+    led_tx: UnboundedSender<DeviceCommand>,
+    device: Devices,
+    lua: &Lua,
+    api: &mlua::Table,
+) -> Result<()> {
+    let set_led = lua.create_function(
+        move |_lua, (key, state, led): (u32, String, Option<String>)| {
+            trace!("Set LED fired from Lua: {} {} {:?}", key, state, led);
+            if !device.has_key(key) {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "{} has no key {}",
+                    device.name(),
+                    key
+                )));
+            }
+            let state = match state.as_str() {
+                "off" => LedState::Off,
+                "on" => LedState::On,
+                "flash" => LedState::Flash,
+                _ => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "Unknown LED state: {}",
+                        state
+                    )))
+                }
+            };
+            let led = match led.as_deref() {
+                None | Some("blue") => Led::Blue,
+                Some("red") => Led::Red,
+                Some(led) => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "Unknown LED color: {}",
+                        led
+                    )))
+                }
+            };
+
+            if let Err(e) = led_tx.send(DeviceCommand::Backlight { key, led, state }) {
+                error!("Unable to send value into device channel: {}", e);
+            }
+            Ok(())
+        },
+    )?;
+    api.set("setLed", set_led)?;
 
     Ok(())
 }
 
+fn define_toggles(toggles: Arc<StdMutex<Toggles>>, lua: &Lua, api: &mlua::Table) -> Result<()> {
+    let is_toggled =
+        lua.create_function(move |_lua, key: u32| Ok(toggles.lock().unwrap().is_latched(key)))?;
+    api.set("isToggled", is_toggled)?;
+
+    Ok(())
+}
+
+/// Reloads scripts as their files change. A save usually arrives as several
+/// events, so changes are collected until the directory has been quiet for a
+/// moment and each changed file is reloaded once.
 async fn script_watcher(
     script: Arc<Mutex<Script>>,
     mut rx: Receiver<Result<NotifyEvent, NotifyError>>,
 ) {
-    loop {
-        if let Some(Ok(event)) = rx.recv().await {
-            if event.kind
-                == notify::EventKind::Modify(notify::event::ModifyKind::Data(
-                    notify::event::DataChange::Content,
-                ))
-            {
-                let mut script = script.lock().await;
+    while let Some(result) = rx.recv().await {
+        let mut changed = BTreeSet::new();
+        let mut next = Some(result);
 
-                if let Some(path) = event.paths.first() {
-                    info!("Updating script: {}", path.display());
-                    if let Err(e) = script.load_script(path) {
-                        error!("Unable to load script: {} -- {}", path.display(), e);
+        while let Some(result) = next {
+            match result {
+                Ok(event) if event.kind.is_remove() => {
+                    for path in event.paths.iter().filter(|path| is_script(path)) {
+                        warn!("Script removed, keeping loaded version: {}", path.display());
                     }
                 }
-            } else {
-                debug!("Received non-file-update notify event: {:?}", event);
+                Ok(event) if is_file_change(&event) => {
+                    changed.extend(event.paths.into_iter().filter(|path| is_script(path)));
+                }
+                Ok(event) => debug!("Received non-file-update notify event: {:?}", event),
+                Err(e) => error!("Script watcher error: {}", e),
+            }
+
+            next = timeout(WATCH_DEBOUNCE, rx.recv()).await.ok().flatten();
+        }
+
+        if changed.is_empty() {
+            continue;
+        }
+
+        let mut script = script.lock().await;
+        for path in changed.into_iter().filter(|path| path.is_file()) {
+            script.reload(&path);
+        }
+    }
+}
+
+fn is_script(path: &Path) -> bool {
+    path.extension() == Some("lua".as_ref())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Limits;
+    use crate::helper::TempDir;
+
+    fn mapping(key: u32, script: &Path) -> String {
+        format!(
+            "[[mappings]]\nkey = {}\nscript = '{}'\n",
+            key,
+            script.display()
+        )
+    }
+
+    fn write_config(dir: &TempDir, mappings: &str) -> Arc<Mutex<Config>> {
+        let path = dir.path().join("config.toml");
+        fs::write(&path, format!("device = 'Dummy'\n{}", mappings)).unwrap();
+        Config::new(&path).unwrap()
+    }
+
+    /// Starts scripts for the config in `dir`, keeping their state there too
+    /// rather than in the user's own state directory.
+    async fn start(dir: &TempDir, mappings: &str) -> Arc<Mutex<Script>> {
+        let config = write_config(dir, mappings);
+        let (enigo_tx, _enigo_rx) = channel(32);
+        let (device_tx, _device_rx) = channel(32);
+        let state_dir = Some(dir.path().to_path_buf());
+        Script::with_state_dir(config, enigo_tx, device_tx, state_dir)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_set_led_checks_key_and_keeps_order() {
+        let lua = Lua::new();
+        let api = lua.create_table().unwrap();
+        let (device_tx, mut device_rx) = channel(1);
+        define_device(forward_in_order(device_tx), Devices::XK68JS, &lua, &api).unwrap();
+        lua.globals()
+            .set("setLed", api.get::<_, Function>("setLed").unwrap())
+            .unwrap();
+
+        assert!(lua.load("setLed(80, 'on')").exec().is_err());
+        assert!(lua.load("setLed(4294967295, 'on', 'red')").exec().is_err());
+
+        lua.load("for i = 0, 9 do setLed(i, 'on') end")
+            .exec()
+            .unwrap();
+        for i in 0..10 {
+            match device_rx.recv().await {
+                Some(DeviceCommand::Backlight { key, .. }) => assert_eq!(key, i),
+                command => panic!("unexpected command: {:?}", command),
             }
         }
     }
+
+    #[tokio::test]
+    async fn test_failed_reload_keeps_running_config() {
+        let dir = TempDir::new("failed-reload");
+        let good = dir.path().join("Good.lua");
+        fs::write(&good, "Version = 1\nGood = {}\nfunction Good.Press() end\n").unwrap();
+
+        let script = start(&dir, &mapping(0, &good)).await;
+        let mut script = script.lock().await;
+
+        fs::write(&good, "Version = 2\nGood = {}\n").unwrap();
+        let missing = dir.path().join("Missing.lua");
+        let config = write_config(&dir, &(mapping(0, &good) + &mapping(1, &missing)));
+        assert!(script.load_mapping(&*config.lock().await).is_err());
+
+        let env: Table = script.lua.registry_value(&script.envs[&good]).unwrap();
+        assert_eq!(env.get::<_, i64>("Version").unwrap(), 1);
+
+        let table = ScriptTable {
+            path: good,
+            name: String::from("Good"),
+        };
+        assert!(matches!(
+            &script.keymaps.get(0).unwrap().target,
+            Target::Script(mapped) if *mapped == table
+        ));
+        assert!(script.keymaps.get(1).is_none());
+        assert!(script.has_method(&table, "Press"));
+    }
+
+    #[tokio::test]
+    async fn test_new_limits_apply_while_loading() {
+        let dir = TempDir::new("new-limits");
+        let runaway = dir.path().join("Runaway.lua");
+        let good = dir.path().join("Good.lua");
+        fs::write(&runaway, "while true do end\n").unwrap();
+        fs::write(&good, "Good = {}\n").unwrap();
+
+        let script = start(&dir, &mapping(0, &good)).await;
+        let mut script = script.lock().await;
+
+        let started = Instant::now();
+        let config = write_config(
+            &dir,
+            &format!("[limits]\ntime = 50\n{}", mapping(0, &runaway)),
+        );
+        assert!(script.load_mapping(&*config.lock().await).is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(script.limiter.limits(), Limits::default());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_script_is_not_reloaded() {
+        let dir = TempDir::new("dropped-script");
+        let kept = dir.path().join("Kept.lua");
+        let dropped = dir.path().join("Dropped.lua");
+        fs::write(&kept, "Kept = {}\nfunction Kept.Press() end\n").unwrap();
+        fs::write(
+            &dropped,
+            "on(\"any\", function() end)\nsetInterval(function() end, 1000)\n",
+        )
+        .unwrap();
+
+        let script = start(&dir, &(mapping(0, &kept) + &mapping(1, &dropped))).await;
+        let mut script = script.lock().await;
+        assert!(script.subscriptions.lock().unwrap().contains(&dropped));
+
+        let config = write_config(&dir, &mapping(0, &kept));
+        script.load_mapping(&*config.lock().await).unwrap();
+        assert!(!script.envs.contains_key(&dropped));
+
+        // Saving the file the config stopped using doesn't bring it back.
+        fs::write(&dropped, fs::read_to_string(&dropped).unwrap()).unwrap();
+        script.reload(&dropped);
+        assert!(!script.envs.contains_key(&dropped));
+        assert!(!script.subscriptions.lock().unwrap().contains(&dropped));
+        assert!(script
+            .timers
+            .lock()
+            .unwrap()
+            .started_by(&dropped)
+            .is_empty());
+        assert!(script.envs.contains_key(&kept));
+    }
 }
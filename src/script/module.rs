@@ -0,0 +1,149 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use {
+    log::trace,
+    mlua::{Function, Lua, Table},
+};
+
+use crate::{constants::SCRIPT_FILE_PATHS, helper::parse_path};
+
+/// Lua side of `require`. Each environment gets its own copy, so modules are
+/// loaded once per script, into that script's environment.
+const PRELUDE: &str = r#"
+local env, load_module = ...
+local pcall, error, type = pcall, error, type
+local loaded, loading = {}, {}
+
+return function(name)
+    if type(name) ~= "string" then
+        error("bad argument to require: module name must be a string", 2)
+    end
+
+    local value = loaded[name]
+    if value == nil then
+        if loading[name] then
+            error("loop requiring module '" .. name .. "'", 2)
+        end
+
+        loading[name] = true
+        local ok, result = pcall(function()
+            local chunk, path = load_module(name, env)
+            return chunk(name, path)
+        end)
+        loading[name] = nil
+
+        if not ok then
+            error(result, 0)
+        end
+        value = result
+
+        if value == nil then
+            value = true
+        end
+        loaded[name] = value
+    end
+
+    return value
+end
+"#;
+
+/// Which scripts required which module files, so a change to a module reloads
+/// the scripts that depend on it.
+#[derive(Debug, Default)]
+pub struct Modules {
+    dependents: HashMap<PathBuf, HashSet<PathBuf>>,
+}
+
+impl Modules {
+    /// Forgets what `script` required, before it's loaded again. Returns the
+    /// modules it required, to `restore` if loading fails.
+    pub fn forget(&mut self, script: &Path) -> Vec<PathBuf> {
+        let mut required = vec![];
+        self.dependents.retain(|module, scripts| {
+            if scripts.remove(script) {
+                required.push(module.clone());
+            }
+            !scripts.is_empty()
+        });
+        required
+    }
+
+    /// Puts back what a script required before a load of it failed, so the
+    /// version still running keeps reloading when its modules change.
+    pub fn restore(&mut self, script: &Path, required: Vec<PathBuf>) {
+        self.forget(script);
+        for module in required {
+            self.add(module, script);
+        }
+    }
+
+    pub fn dependents(&self, module: &Path) -> Vec<PathBuf> {
+        match self.dependents.get(module) {
+            Some(scripts) => scripts.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    fn add(&mut self, module: PathBuf, script: &Path) {
+        self.dependents
+            .entry(module)
+            .or_default()
+            .insert(PathBuf::from(script));
+    }
+}
+
+/// Sets `require` in the environment of the script at `script`.
+pub fn define_require(
+    modules: Arc<StdMutex<Modules>>,
+    lua: &Lua,
+    env: &Table,
+    script: &Path,
+) -> mlua::Result<()> {
+    let script = PathBuf::from(script);
+    let load_module = lua.create_function(move |lua, (name, env): (String, Table)| {
+        trace!("Require fired from Lua: {}", name);
+        let path = find_module(&name).ok_or_else(|| {
+            mlua::Error::RuntimeError(format!(
+                "module '{}' not found in the script directories",
+                name
+            ))
+        })?;
+        let source = fs::read_to_string(&path).map_err(mlua::Error::external)?;
+        let path = fs::canonicalize(&path).map_err(mlua::Error::external)?;
+
+        let chunk = lua
+            .load(&source)
+            .set_name(format!("@{}", path.display()))?
+            .set_environment(env)?
+            .into_function()?;
+
+        modules.lock().unwrap().add(path.clone(), &script);
+
+        Ok((chunk, path.to_string_lossy().into_owned()))
+    })?;
+
+    let require: Function = lua.load(PRELUDE).call((env.clone(), load_module))?;
+    env.set("require", require)
+}
+
+/// Looks for `name` (dots separating directories, as in `util.strings`) in
+/// each script directory and then its `lib/` subfolder.
+fn find_module(name: &str) -> Option<PathBuf> {
+    if name.is_empty() || name.contains(['/', '\\']) {
+        return None;
+    }
+
+    let relative: PathBuf = name.split('.').collect();
+    let relative = relative.with_extension("lua");
+
+    SCRIPT_FILE_PATHS
+        .iter()
+        .map(|path| parse_path(path))
+        .flat_map(|dir| [dir.join(&relative), dir.join("lib").join(&relative)])
+        .find(|path| path.is_file())
+}
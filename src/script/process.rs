@@ -0,0 +1,433 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    process::Stdio,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use {
+    log::{error, trace, warn},
+    mlua::{Function, Lua, MultiValue, RegistryKey, Table, UserData, Value},
+    tokio::{
+        io::{AsyncBufReadExt, AsyncRead, BufReader},
+        process::Command,
+        sync::Mutex,
+        time::timeout,
+    },
+};
+
+use crate::{constants::MAX_PROCESS_OUTPUT, script::Script};
+
+/// `exec` checks its arguments in Rust, then yields so the scheduler parks the
+/// handler until the child exits instead of blocking on it. Outside a handler
+/// there's nothing to park, so it fails before any callbacks are registered.
+const PRELUDE: &str = r#"
+local yield, isyieldable, error, prepare = coroutine.yield, coroutine.isyieldable, error, ...
+
+return function(command, options)
+    if not isyieldable() then
+        error("exec can only be called from a handler", 2)
+    end
+    return yield("scriptkeys:exec", prepare(command, options))
+end
+"#;
+
+/// A command to run along with its options, handed from Lua to the task that
+/// runs it.
+pub struct Process {
+    program: String,
+    args: Vec<String>,
+    cwd: Option<PathBuf>,
+    env: HashMap<String, String>,
+    timeout: Option<u64>,
+    on_stdout: Option<RegistryKey>,
+    on_stderr: Option<RegistryKey>,
+    on_exit: Option<RegistryKey>,
+}
+
+impl UserData for Process {}
+
+#[derive(Debug, Default)]
+struct Output {
+    code: Option<i32>,
+    stdout: String,
+    stderr: String,
+    timed_out: bool,
+}
+
+impl Process {
+    /// Builds a process from the Lua arguments. `command` is either a table of
+    /// program and arguments, or a string which is split on whitespace unless
+    /// `options.shell` is set, in which case it's passed to the system shell.
+    fn from_lua(lua: &Lua, command: Value, options: Option<Table>) -> mlua::Result<Self> {
+        let shell = match &options {
+            Some(options) => options.get::<_, Option<bool>>("shell")?.unwrap_or(false),
+            None => false,
+        };
+
+        let mut words: Vec<String> = match (command, shell) {
+            (Value::String(command), true) => shell_command(command.to_str()?),
+            (Value::String(command), false) if command.to_str()?.contains(['"', '\'']) => {
+                return Err(mlua::Error::RuntimeError(String::from(
+                    "command strings are split on whitespace, so quoting doesn't work; \
+                     pass a table of arguments or set shell = true",
+                )))
+            }
+            (Value::String(command), false) => command
+                .to_str()?
+                .split_whitespace()
+                .map(String::from)
+                .collect(),
+            (Value::Table(command), false) => {
+                command.sequence_values().collect::<Result<_, _>>()?
+            }
+            (Value::Table(_), true) => {
+                return Err(mlua::Error::RuntimeError(String::from(
+                    "shell commands must be a string",
+                )))
+            }
+            _ => {
+                return Err(mlua::Error::RuntimeError(String::from(
+                    "command must be a string or a table",
+                )))
+            }
+        };
+
+        if words.is_empty() {
+            return Err(mlua::Error::RuntimeError(String::from("command is empty")));
+        }
+        let program = words.remove(0);
+
+        let mut process = Self {
+            program,
+            args: words,
+            cwd: None,
+            env: HashMap::new(),
+            timeout: None,
+            on_stdout: None,
+            on_stderr: None,
+            on_exit: None,
+        };
+
+        if let Some(options) = options {
+            process.cwd = options.get::<_, Option<String>>("cwd")?.map(PathBuf::from);
+            process.env = options
+                .get::<_, Option<HashMap<String, String>>>("env")?
+                .unwrap_or_default();
+            process.timeout = options.get("timeout")?;
+
+            let callback = |name: &str| -> mlua::Result<Option<RegistryKey>> {
+                match options.get::<_, Option<Function>>(name)? {
+                    Some(func) => Ok(Some(lua.create_registry_value(func)?)),
+                    None => Ok(None),
+                }
+            };
+            process.on_stdout = callback("onStdout")?;
+            process.on_stderr = callback("onStderr")?;
+            process.on_exit = callback("onExit")?;
+        }
+
+        Ok(process)
+    }
+
+    fn name(&self) -> String {
+        format!("process {}", self.program)
+    }
+
+    /// Releases the callbacks. Must be called under the script lock.
+    fn release(self, lua: &Lua) {
+        for key in [self.on_stdout, self.on_stderr, self.on_exit]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(err) = lua.remove_registry_value(key) {
+                error!("Failed to release process callback: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn shell_command(command: &str) -> Vec<String> {
+    vec![
+        String::from("cmd"),
+        String::from("/C"),
+        String::from(command),
+    ]
+}
+
+#[cfg(not(target_os = "windows"))]
+fn shell_command(command: &str) -> Vec<String> {
+    vec![
+        String::from("sh"),
+        String::from("-c"),
+        String::from(command),
+    ]
+}
+
+pub fn define_process(
+    script: Weak<Mutex<Script>>,
+    lua: &Lua,
+    api: &mlua::Table,
+) -> anyhow::Result<()> {
+    let prepare = lua.create_function(|lua, (command, options): (Value, Option<Table>)| {
+        Process::from_lua(lua, command, options)
+    })?;
+    let exec: Function = lua.load(PRELUDE).call(prepare)?;
+    api.set("exec", exec)?;
+
+    let spawn = lua.create_function(move |lua, (command, options): (Value, Option<Table>)| {
+        let process = Process::from_lua(lua, command, options)?;
+        trace!("Spawn fired from Lua: {}", process.program);
+        tokio::spawn(spawn_task(script.clone(), process));
+        Ok(())
+    })?;
+    api.set("spawn", spawn)?;
+
+    Ok(())
+}
+
+/// Starts the command of a built-in `run` mapping without waiting for it. Its
+/// output is discarded; failing to start or a non-zero exit is logged.
+pub fn run_detached(command: &[String]) {
+    let (program, args) = match command.split_first() {
+        Some(command) => command,
+        None => return,
+    };
+
+    let mut child = match Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) => {
+            error!("Failed to start {}: {}", program, err);
+            return;
+        }
+    };
+
+    let program = program.clone();
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if !status.success() => warn!("{} exited with {}", program, status),
+            Ok(_) => trace!("{} exited", program),
+            Err(err) => error!("Failed to wait for {}: {}", program, err),
+        }
+    });
+}
+
+/// Runs the process for a handler parked in `exec`, then resumes the handler
+/// with the result.
+pub async fn exec_task(script: Weak<Mutex<Script>>, id: u64, ticket: u64, process: Process) {
+    let output = run(&script, &process, true).await;
+
+    if let Some(script) = script.upgrade() {
+        let mut script = script.lock().await;
+        let script = &mut *script;
+
+        match output_table(&script.lua, &output) {
+            Ok(table) => {
+                let args = MultiValue::from_vec(vec![Value::Table(table)]);
+                script.coroutines.wake(&script.lua, id, ticket, args);
+            }
+            Err(err) => error!("Failed to execute script ({}): {}", process.name(), err),
+        }
+        process.release(&script.lua);
+    }
+}
+
+/// Runs a process started by `spawn`, calling its `onExit` callback with the
+/// result once it exits.
+async fn spawn_task(script: Weak<Mutex<Script>>, process: Process) {
+    let output = run(&script, &process, process.on_exit.is_some()).await;
+
+    if let Some(script) = script.upgrade() {
+        let mut script = script.lock().await;
+        let script = &mut *script;
+
+        if let Some(key) = &process.on_exit {
+            let result = script
+                .lua
+                .registry_value::<Function>(key)
+                .and_then(|func| Ok((func, output_table(&script.lua, &output)?)));
+
+            match result {
+                Ok((func, table)) => {
+                    let name = process.name();
+                    script
+                        .coroutines
+                        .spawn(&script.lua, &name, func, None, table)
+                }
+                Err(err) => error!("Failed to execute script ({}): {}", process.name(), err),
+            }
+        }
+        process.release(&script.lua);
+    }
+}
+
+/// Runs the process to completion. Its output is only read if there's a
+/// callback for it or `keep_output` asks for it in the result, so a chatty
+/// program nobody listens to costs nothing.
+async fn run(script: &Weak<Mutex<Script>>, process: &Process, keep_output: bool) -> Output {
+    let stdio = |callback: &Option<RegistryKey>| match keep_output || callback.is_some() {
+        true => Stdio::piped(),
+        false => Stdio::null(),
+    };
+
+    let mut command = Command::new(&process.program);
+    command
+        .args(&process.args)
+        .envs(&process.env)
+        .stdin(Stdio::null())
+        .stdout(stdio(&process.on_stdout))
+        .stderr(stdio(&process.on_stderr))
+        .kill_on_drop(true);
+    if let Some(cwd) = &process.cwd {
+        command.current_dir(cwd);
+    }
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            return Output {
+                stderr: format!("failed to start {}: {}", process.program, err),
+                ..Default::default()
+            }
+        }
+    };
+
+    let mut output = Output::default();
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let finished = async {
+        let (_, _, status) = tokio::join!(
+            read_lines(
+                script,
+                stdout,
+                process.on_stdout.as_ref(),
+                keep_output.then_some(&mut output.stdout)
+            ),
+            read_lines(
+                script,
+                stderr,
+                process.on_stderr.as_ref(),
+                keep_output.then_some(&mut output.stderr)
+            ),
+            child.wait(),
+        );
+        status.ok().and_then(|status| status.code())
+    };
+
+    // On a timeout the output read so far is kept.
+    let result = match process.timeout {
+        Some(ms) => timeout(Duration::from_millis(ms), finished).await.ok(),
+        None => Some(finished.await),
+    };
+
+    match result {
+        Some(code) => output.code = code,
+        None => {
+            if let Err(err) = child.kill().await {
+                error!("Failed to kill {}: {}", process.program, err);
+            }
+            output.timed_out = true;
+        }
+    }
+
+    output
+}
+
+/// Collects a stream's output into `output`, up to `MAX_PROCESS_OUTPUT`
+/// bytes, handing each line to `callback` as a new coroutine if there is one.
+/// Invalid UTF-8 is replaced rather than ending the read, which would leave
+/// the child blocked on a full pipe.
+async fn read_lines<R: AsyncRead + Unpin>(
+    script: &Weak<Mutex<Script>>,
+    stream: Option<R>,
+    callback: Option<&RegistryKey>,
+    mut output: Option<&mut String>,
+) {
+    let mut reader = match stream {
+        Some(stream) => BufReader::new(stream),
+        None => return,
+    };
+
+    let mut buf = vec![];
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if buf.ends_with(b"\n") {
+            buf.pop();
+            if buf.ends_with(b"\r") {
+                buf.pop();
+            }
+        }
+
+        let line = String::from_utf8_lossy(&buf);
+        if let (Some(key), Some(script)) = (callback, script.upgrade()) {
+            call_with_line(&script, key, &line).await;
+        }
+        // Once a line doesn't fit the rest is dropped, so what's kept is
+        // always a prefix of the output.
+        if let Some(kept) = &mut output {
+            if kept.len() + line.len() < MAX_PROCESS_OUTPUT {
+                kept.push_str(&line);
+                kept.push('\n');
+            } else {
+                output = None;
+            }
+        }
+    }
+}
+
+async fn call_with_line(script: &Arc<Mutex<Script>>, key: &RegistryKey, line: &str) {
+    let mut script = script.lock().await;
+    let script = &mut *script;
+
+    match script.lua.registry_value::<Function>(key) {
+        Ok(func) => script
+            .coroutines
+            .spawn(&script.lua, "process output", func, None, line),
+        Err(err) => error!("Failed to find process callback: {}", err),
+    };
+}
+
+fn output_table<'lua>(lua: &'lua Lua, output: &Output) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set("code", output.code)?;
+    table.set("stdout", output.stdout.as_str())?;
+    table.set("stderr", output.stderr.as_str())?;
+    table.set("timedOut", output.timed_out)?;
+
+    Ok(table)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_lines_survives_invalid_utf8() {
+        let stream: &[u8] = b"one\r\nt\xffo\nthree";
+        let mut output = String::new();
+        read_lines(&Weak::new(), Some(stream), None, Some(&mut output)).await;
+        assert_eq!(output, "one\nt\u{fffd}o\nthree\n");
+    }
+
+    #[tokio::test]
+    async fn test_read_lines_caps_output() {
+        let line = "x".repeat(1000) + "\n";
+        let text = line.repeat(MAX_PROCESS_OUTPUT / 1000 + 10);
+        let mut output = String::new();
+        read_lines(&Weak::new(), Some(text.as_bytes()), None, Some(&mut output)).await;
+        assert!(output.len() < MAX_PROCESS_OUTPUT);
+        assert!(text.starts_with(&output));
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use log::{debug, trace};
+
+use crate::device::{Action, Event};
+
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    /// The event isn't part of a sequence and should be dispatched as usual.
+    Pass,
+    /// The event was consumed by an in-progress sequence.
+    Consumed,
+    /// The event was held back by an in-progress sequence, which times out
+    /// unless another key continues it. The ticket identifies this wait to
+    /// `expire`.
+    Held(u64),
+    /// The event completed the sequence at the given index.
+    Matched(usize),
+}
+
+/// An event held back while a sequence was in progress, along with when its
+/// key was pressed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Held {
+    pub event: Event,
+    pub pressed: Option<Instant>,
+}
+
+/// What to do with an event: first dispatch the held events in `replay`, then
+/// handle the event itself as `step` says.
+#[derive(Debug, PartialEq)]
+pub struct Outcome {
+    pub replay: Vec<Held>,
+    pub step: Step,
+}
+
+impl Outcome {
+    fn new(step: Step) -> Self {
+        Self {
+            replay: vec![],
+            step,
+        }
+    }
+}
+
+/// State machine sitting in front of the key dispatch. Presses of a key that
+/// starts a sequence are held back until the sequence completes. If it's
+/// cancelled, times out or a key doesn't continue it, the held events are
+/// replayed so the keys still reach their own mappings. Releases of keys a
+/// completed sequence consumed are consumed as well so scripts never see a
+/// lone `Release`.
+#[derive(Debug, Default)]
+pub struct Sequencer {
+    sequences: Vec<Vec<u32>>,
+    timeout: Duration,
+    cancel: Option<u32>,
+    progress: Vec<u32>,
+    held: Vec<Held>,
+    down: HashSet<u32>,
+    last_press: Option<Instant>,
+    ticket: u64,
+    swallowed: HashSet<u32>,
+}
+
+impl Sequencer {
+    pub fn new(sequences: Vec<Vec<u32>>, timeout: Duration, cancel: Option<u32>) -> Self {
+        Self {
+            sequences,
+            timeout,
+            cancel,
+            ..Default::default()
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn process(&mut self, event: &Event, pressed: Option<Instant>) -> Outcome {
+        self.process_at(event, pressed, Instant::now())
+    }
+
+    /// Ends the sequence in progress if it's still waiting on `ticket`,
+    /// returning the events to replay.
+    pub fn expire(&mut self, ticket: u64) -> Vec<Held> {
+        match !self.progress.is_empty() && ticket == self.ticket {
+            true => {
+                debug!("Key sequence timed out: {:?}", self.progress);
+                self.abandon()
+            }
+            false => vec![],
+        }
+    }
+
+    fn process_at(&mut self, event: &Event, pressed: Option<Instant>, now: Instant) -> Outcome {
+        let held = Held {
+            event: event.clone(),
+            pressed,
+        };
+
+        if event.action == Action::Release {
+            if self.swallowed.remove(&event.key) {
+                return Outcome::new(Step::Consumed);
+            }
+            if self.down.remove(&event.key) {
+                self.held.push(held);
+                return Outcome::new(Step::Consumed);
+            }
+            return Outcome::new(Step::Pass);
+        }
+
+        let mut replay = vec![];
+        if let Some(last_press) = self.last_press {
+            if now.duration_since(last_press) > self.timeout {
+                debug!("Key sequence timed out: {:?}", self.progress);
+                replay = self.abandon();
+            }
+        }
+
+        if !self.progress.is_empty() && Some(event.key) == self.cancel {
+            debug!("Key sequence cancelled: {:?}", self.progress);
+            replay.extend(self.abandon());
+            self.swallowed.insert(event.key);
+            return Outcome {
+                replay,
+                step: Step::Consumed,
+            };
+        }
+
+        let mut candidate = self.progress.clone();
+        candidate.push(event.key);
+
+        if let Some(index) = self.sequences.iter().position(|seq| *seq == candidate) {
+            trace!("Key sequence matched: {:?}", candidate);
+            self.swallowed.extend(self.down.drain());
+            self.swallowed.insert(event.key);
+            self.reset();
+            return Outcome {
+                replay,
+                step: Step::Matched(index),
+            };
+        }
+
+        if self.sequences.iter().any(|seq| seq.starts_with(&candidate)) {
+            trace!("Key sequence in progress: {:?}", candidate);
+            return Outcome {
+                replay,
+                step: self.hold(candidate, held, now),
+            };
+        }
+
+        if !self.progress.is_empty() {
+            debug!("No key sequence matches: {:?}", candidate);
+            replay.extend(self.abandon());
+
+            // The key that broke the sequence may start another.
+            if self
+                .sequences
+                .iter()
+                .any(|seq| seq.first() == Some(&event.key))
+            {
+                return Outcome {
+                    replay,
+                    step: self.hold(vec![event.key], held, now),
+                };
+            }
+        }
+
+        Outcome {
+            replay,
+            step: Step::Pass,
+        }
+    }
+
+    fn hold(&mut self, progress: Vec<u32>, held: Held, now: Instant) -> Step {
+        self.progress = progress;
+        self.down.insert(held.event.key);
+        self.held.push(held);
+        self.last_press = Some(now);
+        self.ticket += 1;
+        Step::Held(self.ticket)
+    }
+
+    /// Gives up on the sequence in progress, returning what it held back.
+    /// Keys still down are released through the usual dispatch.
+    fn abandon(&mut self) -> Vec<Held> {
+        let held = std::mem::take(&mut self.held);
+        self.down.clear();
+        self.reset();
+        held
+    }
+
+    fn reset(&mut self) {
+        self.progress.clear();
+        self.held.clear();
+        self.last_press = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn press(key: u32) -> Event {
+        Event {
+            key,
+            action: Action::Press,
+        }
+    }
+
+    fn release(key: u32) -> Event {
+        Event {
+            key,
+            action: Action::Release,
+        }
+    }
+
+    fn keys(outcome: &Outcome) -> Vec<(u32, Action)> {
+        outcome
+            .replay
+            .iter()
+            .map(|held| (held.event.key, held.event.action))
+            .collect()
+    }
+
+    #[test]
+    fn test_sequence() {
+        let mut sequencer = Sequencer::new(
+            vec![vec![0, 5, 6], vec![0, 7]],
+            Duration::from_secs(1),
+            Some(9),
+        );
+        let start = Instant::now();
+        let mut process = |event: &Event, now: Instant| sequencer.process_at(event, None, now);
+
+        assert_eq!(process(&press(3), start).step, Step::Pass);
+        assert_eq!(process(&release(3), start).step, Step::Pass);
+
+        assert_eq!(process(&press(0), start).step, Step::Held(1));
+        assert_eq!(process(&release(0), start).step, Step::Consumed);
+        assert_eq!(process(&press(5), start).step, Step::Held(2));
+        assert_eq!(process(&press(6), start).step, Step::Matched(0));
+        assert_eq!(process(&release(5), start).step, Step::Consumed);
+        assert_eq!(process(&release(6), start).step, Step::Consumed);
+
+        // Cancelling replays the leader and consumes the cancel key.
+        process(&press(0), start);
+        let cancelled = process(&press(9), start);
+        assert_eq!(cancelled.step, Step::Consumed);
+        assert_eq!(keys(&cancelled), [(0, Action::Press)]);
+        assert_eq!(process(&release(0), start).step, Step::Pass);
+        assert_eq!(process(&release(9), start).step, Step::Consumed);
+
+        // A key that doesn't continue the sequence is dispatched after the
+        // keys held back before it.
+        process(&press(0), start);
+        process(&release(0), start);
+        let mismatched = process(&press(3), start);
+        assert_eq!(mismatched.step, Step::Pass);
+        assert_eq!(
+            keys(&mismatched),
+            [(0, Action::Press), (0, Action::Release)]
+        );
+
+        // A late press replays the timed out sequence before continuing.
+        process(&press(0), start);
+        let late = process(&press(7), start + Duration::from_secs(2));
+        assert_eq!(late.step, Step::Pass);
+        assert_eq!(keys(&late), [(0, Action::Press)]);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut sequencer = Sequencer::new(vec![vec![0, 7]], Duration::from_secs(1), None);
+        let start = Instant::now();
+
+        let Step::Held(ticket) = sequencer.process_at(&press(0), None, start).step else {
+            panic!("leader wasn't held");
+        };
+        assert_eq!(sequencer.expire(ticket + 1), vec![]);
+        assert_eq!(sequencer.expire(ticket).len(), 1);
+        assert_eq!(sequencer.expire(ticket), vec![]);
+        assert_eq!(
+            sequencer.process_at(&release(0), None, start).step,
+            Step::Pass
+        );
+    }
+}
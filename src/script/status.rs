@@ -0,0 +1,177 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use {
+    anyhow::Result,
+    chrono::Local,
+    log::{debug, error},
+    serde::{Deserialize, Serialize},
+    tokio::sync::watch,
+};
+
+use crate::{
+    constants::{STATUS_FILE_NAME, STATUS_SAVE_INTERVAL},
+    helper::{find_state_location, spawn_writer},
+};
+
+/// The handler name errors are recorded under when a script fails to load.
+pub const LOADING: &str = "load";
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct StatusFile {
+    #[serde(default)]
+    scripts: BTreeMap<String, ScriptStatus>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+struct ScriptStatus {
+    #[serde(default)]
+    loaded: Option<String>,
+    #[serde(default)]
+    last_error: Option<LastError>,
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+struct LastError {
+    time: String,
+    handler: String,
+    message: String,
+}
+
+/// When each script last loaded and the last error it hit, keyed by the
+/// script's path. It's written to disk after every change so `scriptkeys
+/// status` can read it from another process. Each run starts it afresh.
+#[derive(Debug, Default)]
+pub struct Status {
+    file: StatusFile,
+    /// The latest contents for the task writing the file, so handlers don't
+    /// wait on the disk.
+    writer: Option<watch::Sender<String>>,
+}
+
+impl Status {
+    pub fn new(state_dir: Option<&Path>) -> Self {
+        let writer = match state_dir.map(|dir| dir.join(STATUS_FILE_NAME)) {
+            // At most one write every `STATUS_SAVE_INTERVAL`, so a handler
+            // failing on every key press doesn't write the file on every press.
+            Some(path) => Some(spawn_writer(path, STATUS_SAVE_INTERVAL, "script status")),
+            None => {
+                debug!("No location for script status, not persisting");
+                None
+            }
+        };
+
+        let status = Self {
+            file: StatusFile::default(),
+            writer,
+        };
+        status.save();
+        status
+    }
+
+    /// Records a successful load of `script`, clearing an error from a
+    /// previous load. Errors its handlers hit are kept.
+    pub fn loaded(&mut self, script: &str) {
+        let status = self.file.scripts.entry(String::from(script)).or_default();
+        status.loaded = Some(now());
+        if matches!(&status.last_error, Some(last) if last.handler == LOADING) {
+            status.last_error = None;
+        }
+
+        self.save();
+    }
+
+    pub fn failed(&mut self, script: &str, handler: &str, message: &str) {
+        let status = self.file.scripts.entry(String::from(script)).or_default();
+        status.last_error = Some(LastError {
+            time: now(),
+            handler: String::from(handler),
+            message: String::from(message),
+        });
+
+        self.save();
+    }
+
+    fn save(&self) {
+        let writer = match &self.writer {
+            Some(writer) => writer,
+            None => return,
+        };
+
+        match toml::to_string(&self.file) {
+            Ok(contents) => {
+                writer.send_replace(contents);
+            }
+            Err(e) => error!("Couldn't serialize script status: {}", e),
+        }
+    }
+}
+
+fn now() -> String {
+    Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn read_status_file(path: &Path) -> Result<StatusFile> {
+    Ok(toml::from_str(&fs::read_to_string(path)?)?)
+}
+
+/// Prints the status the running scriptkeys last wrote, for `scriptkeys
+/// status`.
+pub fn print_status() -> Result<()> {
+    let file = match find_state_location(STATUS_FILE_NAME) {
+        Some(path) if path.exists() => read_status_file(&path)?,
+        _ => {
+            println!("No script status found. Is scriptkeys running?");
+            return Ok(());
+        }
+    };
+
+    if file.scripts.is_empty() {
+        println!("No scripts loaded.");
+    }
+    for (script, status) in &file.scripts {
+        print!("{}", format_status(script, status));
+    }
+
+    Ok(())
+}
+
+fn format_status(script: &str, status: &ScriptStatus) -> String {
+    let mut output = match &status.loaded {
+        Some(time) => format!("{}: loaded {}\n", script, time),
+        None => format!("{}: not loaded\n", script),
+    };
+
+    if let Some(last) = &status.last_error {
+        output.push_str(&format!(
+            "  last error in {} at {}:\n",
+            last.handler, last.time
+        ));
+        for line in last.message.lines() {
+            output.push_str(&format!("    {}\n", line));
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_loaded_clears_load_error() {
+        let mut status = Status::default();
+        status.failed("A.lua", LOADING, "syntax error");
+        status.loaded("A.lua");
+        assert_eq!(status.file.scripts["A.lua"].last_error, None);
+
+        status.failed("A.lua", "A.Press", "runtime error: boom\nstack traceback:");
+        status.loaded("A.lua");
+        let script = &status.file.scripts["A.lua"];
+        assert_eq!(script.last_error.as_ref().unwrap().handler, "A.Press");
+
+        let output = format_status("A.lua", script);
+        assert!(output.contains("last error in A.Press"));
+        assert!(output.contains("    stack traceback:\n"));
+    }
+}
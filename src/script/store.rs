@@ -0,0 +1,371 @@
+use std::{
+    collections::BTreeMap,
+    ffi::c_void,
+    fs,
+    path::Path,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use {
+    anyhow::Result,
+    log::{debug, error, trace},
+    mlua::{Lua, Table, Value},
+    serde::{Deserialize, Serialize},
+    tokio::sync::watch,
+};
+
+use crate::{
+    constants::{SCRIPT_FILE_PATHS, STORE_FILE_NAME, STORE_SAVE_INTERVAL},
+    helper::{parse_path, spawn_writer},
+};
+
+/// How deeply tables may nest in a stored value.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct StoreFile {
+    #[serde(default)]
+    shared: toml::Table,
+    #[serde(default)]
+    scripts: BTreeMap<String, toml::Table>,
+}
+
+/// Which part of the store a script is reading or writing: its own values,
+/// keyed by `script_namespace`, or the values every script shares.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Namespace {
+    Script(String),
+    Shared,
+}
+
+/// Values scripts keep across restarts. It's written to disk after every
+/// change, by a task so `set` doesn't wait on the disk with the script lock
+/// held. A burst of changes is written once.
+#[derive(Debug, Default)]
+pub struct Store {
+    file: StoreFile,
+    /// The latest contents for the task writing the file.
+    writer: Option<watch::Sender<String>>,
+}
+
+impl Store {
+    pub fn load(state_dir: Option<&Path>) -> Self {
+        let path = state_dir.map(|dir| dir.join(STORE_FILE_NAME));
+
+        let file = match &path {
+            Some(path) if path.exists() => match read_store_file(path) {
+                Ok(file) => file,
+                Err(e) => {
+                    error!("Couldn't read script store ({}): {}", path.display(), e);
+                    StoreFile::default()
+                }
+            },
+            _ => StoreFile::default(),
+        };
+
+        let writer = match path {
+            Some(path) => Some(spawn_writer(path, STORE_SAVE_INTERVAL, "script store")),
+            None => {
+                debug!("No location for script store, not persisting");
+                None
+            }
+        };
+
+        Self { file, writer }
+    }
+
+    pub fn get(&self, namespace: &Namespace, key: &str) -> Option<&toml::Value> {
+        match namespace {
+            Namespace::Script(script) => self.file.scripts.get(script)?.get(key),
+            Namespace::Shared => self.file.shared.get(key),
+        }
+    }
+
+    pub fn set(&mut self, namespace: &Namespace, key: &str, value: toml::Value) {
+        let table = match namespace {
+            Namespace::Script(script) => self.file.scripts.entry(script.clone()).or_default(),
+            Namespace::Shared => &mut self.file.shared,
+        };
+        table.insert(String::from(key), value);
+
+        self.save();
+    }
+
+    pub fn delete(&mut self, namespace: &Namespace, key: &str) {
+        let removed = match namespace {
+            Namespace::Script(script) => {
+                let table = self.file.scripts.get_mut(script);
+                let removed = table.and_then(|table| table.remove(key)).is_some();
+                if self.file.scripts.get(script).is_some_and(|t| t.is_empty()) {
+                    self.file.scripts.remove(script);
+                }
+                removed
+            }
+            Namespace::Shared => self.file.shared.remove(key).is_some(),
+        };
+
+        if removed {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        let writer = match &self.writer {
+            Some(writer) => writer,
+            None => return,
+        };
+
+        match toml::to_string(&self.file) {
+            Ok(contents) => {
+                writer.send_replace(contents);
+            }
+            Err(e) => error!("Couldn't serialize script store: {}", e),
+        }
+    }
+}
+
+fn read_store_file(path: &Path) -> Result<StoreFile> {
+    Ok(toml::from_str(&fs::read_to_string(path)?)?)
+}
+
+/// The namespace of the script at the canonical path `script`: its path
+/// relative to the script directory it's in, such as `tools/Deploy.lua`, so
+/// scripts with the same file name in different folders keep separate values.
+/// Scripts outside the script directories, such as an `init.lua`, use their
+/// full path.
+pub fn script_namespace(script: &Path) -> String {
+    SCRIPT_FILE_PATHS
+        .iter()
+        .filter_map(|dir| parse_path(dir).canonicalize().ok())
+        .find_map(|dir| script.strip_prefix(dir).ok().map(Path::to_path_buf))
+        .map(|relative| {
+            let parts: Vec<_> = relative.iter().map(|part| part.to_string_lossy()).collect();
+            parts.join("/")
+        })
+        .unwrap_or_else(|| script.to_string_lossy().into_owned())
+}
+
+/// Sets `store` in a script's environment: `get`, `set` and `delete` on the
+/// script's own namespace, and the same functions on `store.shared`.
+pub fn define_store(
+    store: Arc<StdMutex<Store>>,
+    lua: &Lua,
+    env: &Table,
+    script: &str,
+) -> mlua::Result<()> {
+    let table = namespace_table(store.clone(), lua, Namespace::Script(String::from(script)))?;
+    table.set("shared", namespace_table(store, lua, Namespace::Shared)?)?;
+    env.set("store", table)
+}
+
+fn namespace_table(
+    store: Arc<StdMutex<Store>>,
+    lua: &Lua,
+    namespace: Namespace,
+) -> mlua::Result<Table<'_>> {
+    let table = lua.create_table()?;
+
+    let (store_copy, namespace_copy) = (store.clone(), namespace.clone());
+    let get = lua.create_function(move |lua, (key, default): (String, Value)| {
+        trace!("Store get fired from Lua: {:?} {}", namespace_copy, key);
+        match store_copy.lock().unwrap().get(&namespace_copy, &key) {
+            Some(value) => to_lua(lua, value),
+            None => Ok(default),
+        }
+    })?;
+    table.set("get", get)?;
+
+    let (store_copy, namespace_copy) = (store.clone(), namespace.clone());
+    let set = lua.create_function(move |_lua, (key, value): (String, Value)| {
+        trace!("Store set fired from Lua: {:?} {}", namespace_copy, key);
+        let mut store = store_copy.lock().unwrap();
+        match value {
+            Value::Nil => store.delete(&namespace_copy, &key),
+            value => store.set(&namespace_copy, &key, to_toml(value)?),
+        }
+        Ok(())
+    })?;
+    table.set("set", set)?;
+
+    let delete = lua.create_function(move |_lua, key: String| {
+        trace!("Store delete fired from Lua: {:?} {}", namespace, key);
+        store.lock().unwrap().delete(&namespace, &key);
+        Ok(())
+    })?;
+    table.set("delete", delete)?;
+
+    Ok(table)
+}
+
+/// Converts a Lua value to TOML. Tables whose keys are exactly `1..n` become
+/// arrays, other tables need string keys. Cyclic and deeply nested tables are
+/// rejected, since the conversion recurses outside the reach of the limits.
+fn to_toml(value: Value) -> mlua::Result<toml::Value> {
+    convert(value, &mut vec![])
+}
+
+/// `to_toml` for a value nested in the tables of `parents`.
+fn convert(value: Value, parents: &mut Vec<*const c_void>) -> mlua::Result<toml::Value> {
+    match value {
+        Value::Boolean(value) => Ok(toml::Value::Boolean(value)),
+        Value::Integer(value) => Ok(toml::Value::Integer(value)),
+        Value::Number(value) => Ok(toml::Value::Float(value)),
+        Value::String(value) => Ok(toml::Value::String(String::from(value.to_str()?))),
+        Value::Table(table) => {
+            if parents.contains(&table.to_pointer()) {
+                return Err(mlua::Error::RuntimeError(String::from(
+                    "can't store a cyclic table",
+                )));
+            }
+            if parents.len() >= MAX_DEPTH {
+                return Err(mlua::Error::RuntimeError(format!(
+                    "can't store tables nested more than {} deep",
+                    MAX_DEPTH
+                )));
+            }
+            parents.push(table.to_pointer());
+            let result = convert_table(table, parents);
+            parents.pop();
+            result
+        }
+        value => Err(mlua::Error::RuntimeError(format!(
+            "can't store a {}",
+            value.type_name()
+        ))),
+    }
+}
+
+fn convert_table(table: Table, parents: &mut Vec<*const c_void>) -> mlua::Result<toml::Value> {
+    let len = table.raw_len();
+    let count = table.clone().pairs::<Value, Value>().count() as i64;
+
+    if len > 0 && len == count {
+        let values = table
+            .sequence_values::<Value>()
+            .map(|value| convert(value?, parents))
+            .collect::<mlua::Result<_>>()?;
+        Ok(toml::Value::Array(values))
+    } else {
+        let mut map = toml::Table::new();
+        for pair in table.pairs::<Value, Value>() {
+            match pair? {
+                (Value::String(key), value) => {
+                    map.insert(String::from(key.to_str()?), convert(value, parents)?);
+                }
+                (key, _) => {
+                    return Err(mlua::Error::RuntimeError(format!(
+                        "can't store table with {} key",
+                        key.type_name()
+                    )))
+                }
+            }
+        }
+        Ok(toml::Value::Table(map))
+    }
+}
+
+pub fn to_lua<'lua>(lua: &'lua Lua, value: &toml::Value) -> mlua::Result<Value<'lua>> {
+    match value {
+        toml::Value::Boolean(value) => Ok(Value::Boolean(*value)),
+        toml::Value::Integer(value) => Ok(Value::Integer(*value)),
+        toml::Value::Float(value) => Ok(Value::Number(*value)),
+        toml::Value::String(value) => Ok(Value::String(lua.create_string(value)?)),
+        toml::Value::Datetime(value) => Ok(Value::String(lua.create_string(&value.to_string())?)),
+        toml::Value::Array(values) => {
+            let table = lua.create_table()?;
+            for value in values {
+                table.raw_push(to_lua(lua, value)?)?;
+            }
+            Ok(Value::Table(table))
+        }
+        toml::Value::Table(map) => {
+            let table = lua.create_table()?;
+            for (key, value) in map {
+                table.set(key.as_str(), to_lua(lua, value)?)?;
+            }
+            Ok(Value::Table(table))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::helper::TempDir;
+
+    #[tokio::test]
+    async fn test_changes_are_written_in_the_background() {
+        let dir = TempDir::new("store-writer");
+        let path = dir.path().join(STORE_FILE_NAME);
+        let namespace = Namespace::Script(String::from("Counter.lua"));
+
+        let mut store = Store::load(Some(dir.path()));
+        for count in 0..100 {
+            store.set(&namespace, "count", toml::Value::Integer(count));
+        }
+        store.set(
+            &Namespace::Shared,
+            "name",
+            toml::Value::String(String::from("a")),
+        );
+        store.delete(&Namespace::Shared, "name");
+
+        tokio::time::sleep(STORE_SAVE_INTERVAL * 3).await;
+        let restored = Store::load(Some(dir.path()));
+        assert_eq!(
+            restored.get(&namespace, "count"),
+            Some(&toml::Value::Integer(99))
+        );
+        assert_eq!(restored.get(&Namespace::Shared, "name"), None);
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let lua = Lua::new();
+        let value: Value = lua
+            .load(r#"{ count = 3, ratio = 0.5, name = "a", recent = { "x", "y" }, nested = { on = true } }"#)
+            .eval()
+            .unwrap();
+
+        let stored = to_toml(value).unwrap();
+        let toml::Value::Table(map) = &stored else {
+            panic!("expected a table, got {:?}", stored);
+        };
+        assert_eq!(map["count"], toml::Value::Integer(3));
+        assert_eq!(map["ratio"], toml::Value::Float(0.5));
+        assert_eq!(
+            map["recent"],
+            toml::Value::Array(vec![
+                toml::Value::String(String::from("x")),
+                toml::Value::String(String::from("y")),
+            ])
+        );
+
+        let restored = to_lua(&lua, &stored).unwrap();
+        assert_eq!(to_toml(restored).unwrap(), stored);
+
+        let func: Value = lua.load("function() end").eval().unwrap();
+        assert!(to_toml(func).is_err());
+    }
+
+    #[test]
+    fn test_nesting() {
+        let lua = Lua::new();
+        let shared: Value = lua
+            .load("local leaf = { 1 } return { a = leaf, b = { leaf } }")
+            .eval()
+            .unwrap();
+        assert!(to_toml(shared).is_ok());
+
+        let cyclic: Value = lua.load("local t = {} t.self = t return t").eval().unwrap();
+        let err = to_toml(cyclic).unwrap_err().to_string();
+        assert!(err.contains("cyclic"), "{}", err);
+
+        let deep: Value = lua
+            .load("local t = {} for i = 1, 100 do t = { t } end return t")
+            .eval()
+            .unwrap();
+        assert!(to_toml(deep).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use {
+    log::trace,
+    mlua::{Function, Lua, RegistryKey, Table, Value},
+};
+
+/// What a handler registered with `on` is called for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Topic {
+    /// A press or release of one key, whether or not it's mapped.
+    Key(u32, &'static str),
+    /// Every press and release.
+    Any,
+    Connect,
+    Disconnect,
+    /// The joystick moved.
+    Joystick,
+    /// The named handler of the mappings to the script, used when the script
+    /// doesn't define it in a table named after the file.
+    Mapped(&'static str),
+}
+
+impl Topic {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "any" => Some(Self::Any),
+            "connect" => Some(Self::Connect),
+            "disconnect" => Some(Self::Disconnect),
+            "joystick" => Some(Self::Joystick),
+            "press" => Some(Self::Mapped("Press")),
+            "release" => Some(Self::Mapped("Release")),
+            "repeat" => Some(Self::Mapped("Repeat")),
+            "on" => Some(Self::Mapped("On")),
+            "off" => Some(Self::Mapped("Off")),
+            _ => None,
+        }
+    }
+
+    fn parse_action(action: &str) -> Option<&'static str> {
+        match action {
+            "press" => Some("press"),
+            "release" => Some("release"),
+            _ => None,
+        }
+    }
+}
+
+/// Handlers scripts registered with `on`, kept per script. Registrations are
+/// collected while a script loads and replace its previous ones only once
+/// the new version is installed.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    scripts: HashMap<PathBuf, Vec<(Topic, RegistryKey)>>,
+    loading: Option<(PathBuf, Vec<(Topic, RegistryKey)>)>,
+}
+
+impl Subscriptions {
+    pub fn begin(&mut self, script: &Path) {
+        self.loading = Some((PathBuf::from(script), vec![]));
+    }
+
+    /// Ends loading a script, returning what it registered apart from its
+    /// running version's registrations.
+    pub fn finish(&mut self) -> Subscriptions {
+        let mut loaded = Subscriptions::default();
+        if let Some((script, registered)) = self.loading.take() {
+            loaded.scripts.insert(script, registered);
+        }
+        loaded
+    }
+
+    /// Takes what the scripts in `loaded` registered, replacing their
+    /// previous registrations.
+    pub fn merge(&mut self, lua: &Lua, loaded: Subscriptions) -> mlua::Result<()> {
+        for (script, registered) in loaded.scripts {
+            release(
+                lua,
+                self.scripts.insert(script, registered).unwrap_or_default(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Drops the registrations of scripts `keep` rejects, once they're no
+    /// longer referenced by the config.
+    pub fn retain(&mut self, lua: &Lua, keep: impl Fn(&Path) -> bool) -> mlua::Result<()> {
+        let dropped: Vec<PathBuf> = self
+            .scripts
+            .keys()
+            .filter(|script| !keep(script))
+            .cloned()
+            .collect();
+
+        for script in dropped {
+            release(lua, self.scripts.remove(&script).unwrap_or_default())?;
+        }
+
+        Ok(())
+    }
+
+    /// Every handler registered for `topic`, along with its script.
+    pub fn matching(&self, topic: &Topic) -> Vec<(&Path, &RegistryKey)> {
+        self.scripts
+            .iter()
+            .flat_map(|(script, registered)| {
+                registered
+                    .iter()
+                    .filter(move |(registered, _)| registered == topic)
+                    .map(move |(_, key)| (script.as_path(), key))
+            })
+            .collect()
+    }
+
+    pub fn mapped(&self, script: &Path, method: &str) -> Option<&RegistryKey> {
+        self.scripts
+            .get(script)?
+            .iter()
+            .find(|(topic, _)| matches!(topic, Topic::Mapped(name) if *name == method))
+            .map(|(_, key)| key)
+    }
+
+    pub fn contains(&self, script: &Path) -> bool {
+        self.scripts
+            .get(script)
+            .is_some_and(|registered| !registered.is_empty())
+    }
+
+    fn add(&mut self, script: &Path, topic: Topic, key: RegistryKey) -> Result<(), RegistryKey> {
+        match &mut self.loading {
+            Some((loading, registered)) if loading == script => {
+                registered.push((topic, key));
+                Ok(())
+            }
+            _ => Err(key),
+        }
+    }
+}
+
+fn release(lua: &Lua, registered: Vec<(Topic, RegistryKey)>) -> mlua::Result<()> {
+    for (_, key) in registered {
+        lua.remove_registry_value(key)?;
+    }
+    Ok(())
+}
+
+/// Sets `on` in the environment of the script at `script`: `on(key, action,
+/// handler)` for one key, or `on(event, handler)` for a named event.
+pub fn define_on(
+    subscriptions: Arc<StdMutex<Subscriptions>>,
+    lua: &Lua,
+    env: &Table,
+    script: &Path,
+) -> mlua::Result<()> {
+    let script = PathBuf::from(script);
+    let on = lua.create_function(move |lua, (first, second, third): (Value, Value, Value)| {
+        let (topic, func) =
+            match (first, second, third) {
+                (Value::Integer(key), Value::String(action), Value::Function(func)) if key >= 0 => {
+                    let action = Topic::parse_action(action.to_str()?).ok_or_else(|| {
+                        mlua::Error::RuntimeError(String::from(
+                            "bad argument to on: key action must be \"press\" or \"release\"",
+                        ))
+                    })?;
+                    (Topic::Key(key as u32, action), func)
+                }
+                (Value::String(name), Value::Function(func), Value::Nil) => {
+                    let name = name.to_str()?;
+                    let topic = Topic::parse(name).ok_or_else(|| {
+                        mlua::Error::RuntimeError(format!(
+                            "bad argument to on: unknown event '{}'",
+                            name
+                        ))
+                    })?;
+                    (topic, func)
+                }
+                _ => return Err(mlua::Error::RuntimeError(String::from(
+                    "bad argument to on: expected on(key, action, handler) or on(event, handler)",
+                ))),
+            };
+        trace!("On fired from Lua: {:?}", topic);
+
+        let key = lua.create_registry_value::<Function>(func)?;
+        if let Err(key) = subscriptions.lock().unwrap().add(&script, topic, key) {
+            lua.remove_registry_value(key)?;
+            return Err(mlua::Error::RuntimeError(String::from(
+                "on can only be called while the script is loading",
+            )));
+        }
+        Ok(())
+    })?;
+
+    env.set("on", on)
+}
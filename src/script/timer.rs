@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex, Weak},
+    time::Duration,
+};
+
+use {
+    log::{error, trace},
+    mlua::{Function, Lua, RegistryKey, Table},
+    tokio::{sync::Mutex, task::JoinHandle, time::sleep},
+};
+
+use crate::{constants::MIN_TIMER_INTERVAL, script::Script};
+
+/// Running Lua timers keyed by the id handed back to the script, along with
+/// the script that started them.
+#[derive(Debug, Default)]
+pub struct Timers {
+    next_id: u64,
+    handles: HashMap<u64, (PathBuf, JoinHandle<()>)>,
+}
+
+impl Timers {
+    /// The timers running for `script`.
+    pub fn started_by(&self, script: &Path) -> Vec<u64> {
+        self.handles
+            .iter()
+            .filter(|(_, (owner, _))| owner == script)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    pub fn cancel(&mut self, ids: &[u64]) {
+        for id in ids {
+            if let Some((_, handle)) = self.handles.remove(id) {
+                handle.abort();
+            }
+        }
+    }
+
+    /// Cancels the timers of scripts `keep` rejects.
+    pub fn retain(&mut self, keep: impl Fn(&Path) -> bool) {
+        self.handles.retain(|_, (owner, handle)| {
+            let kept = keep(owner);
+            if !kept {
+                handle.abort();
+            }
+            kept
+        });
+    }
+}
+
+/// Sets the timer functions in the environment of the script at `script`.
+/// Timers belong to the script, which can only clear its own, and are
+/// cancelled when it's reloaded or no longer used.
+pub fn define_timers(
+    handle: Weak<Mutex<Script>>,
+    timers: Arc<StdMutex<Timers>>,
+    lua: &Lua,
+    env: &Table,
+    script: &Path,
+) -> mlua::Result<()> {
+    let script = PathBuf::from(script);
+
+    let (handle_copy, timers_copy, script_copy) = (handle.clone(), timers.clone(), script.clone());
+    let set_timeout = lua.create_function(move |lua, (func, ms): (Function, u64)| {
+        trace!("Set timeout fired from Lua: {}", ms);
+        let delay = Duration::from_millis(ms);
+        start_timer(
+            lua,
+            &handle_copy,
+            &timers_copy,
+            &script_copy,
+            func,
+            delay,
+            false,
+        )
+    })?;
+    env.set("setTimeout", set_timeout)?;
+
+    let (handle_copy, timers_copy, script_copy) = (handle, timers.clone(), script.clone());
+    let set_interval = lua.create_function(move |lua, (func, ms): (Function, u64)| {
+        trace!("Set interval fired from Lua: {}", ms);
+        let interval = Duration::from_millis(ms).max(MIN_TIMER_INTERVAL);
+        start_timer(
+            lua,
+            &handle_copy,
+            &timers_copy,
+            &script_copy,
+            func,
+            interval,
+            true,
+        )
+    })?;
+    env.set("setInterval", set_interval)?;
+
+    let clear_timer = lua.create_function(move |lua, id: u64| {
+        trace!("Clear timer fired from Lua: {}", id);
+        let mut timers = timers.lock().unwrap();
+        if timers.started_by(&script).contains(&id) {
+            timers.cancel(&[id]);
+        }
+        lua.expire_registry_values();
+        Ok(())
+    })?;
+    env.set("clearTimer", clear_timer)?;
+
+    Ok(())
+}
+
+fn start_timer(
+    lua: &Lua,
+    handle: &Weak<Mutex<Script>>,
+    timers: &Arc<StdMutex<Timers>>,
+    script: &Path,
+    func: Function,
+    duration: Duration,
+    repeat: bool,
+) -> mlua::Result<u64> {
+    let key = lua.create_registry_value(func)?;
+
+    let mut guard = timers.lock().unwrap();
+    guard.next_id += 1;
+    let id = guard.next_id;
+
+    let task = tokio::spawn(timer_task(
+        handle.clone(),
+        timers.clone(),
+        id,
+        key,
+        duration,
+        repeat,
+    ));
+    guard.handles.insert(id, (PathBuf::from(script), task));
+
+    Ok(id)
+}
+
+/// Sleeps for `duration` and then calls the callback under the script lock,
+/// the same way key events are dispatched.
+async fn timer_task(
+    script: Weak<Mutex<Script>>,
+    timers: Arc<StdMutex<Timers>>,
+    id: u64,
+    key: RegistryKey,
+    duration: Duration,
+    repeat: bool,
+) {
+    loop {
+        sleep(duration).await;
+
+        let script = match script.upgrade() {
+            Some(script) => script,
+            None => return,
+        };
+        let mut script = script.lock().await;
+        let script = &mut *script;
+
+        match script.lua.registry_value::<Function>(&key) {
+            Ok(func) => {
+                let name = format!("timer {}", id);
+                script.coroutines.spawn(&script.lua, &name, func, None, ());
+            }
+            Err(err) => error!("Failed to find timer callback ({}): {}", id, err),
+        }
+
+        if !repeat {
+            timers.lock().unwrap().handles.remove(&id);
+            if let Err(err) = script.lua.remove_registry_value(key) {
+                error!("Failed to release timer callback ({}): {}", id, err);
+            }
+            return;
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use {
+    anyhow::Result,
+    log::{debug, error},
+    serde::{Deserialize, Serialize},
+};
+
+use crate::{constants::TOGGLE_FILE_NAME, helper::write_atomic};
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct ToggleFile {
+    latched: BTreeSet<u32>,
+}
+
+/// Latched state of toggle mappings. The state is independent of the loaded
+/// mappings so it survives config reloads, and it's written to disk on every
+/// change so it survives restarts.
+#[derive(Debug, Default)]
+pub struct Toggles {
+    path: Option<PathBuf>,
+    latched: BTreeSet<u32>,
+}
+
+impl Toggles {
+    pub fn load(state_dir: Option<&Path>) -> Self {
+        let path = state_dir.map(|dir| dir.join(TOGGLE_FILE_NAME));
+
+        let latched = match &path {
+            Some(path) if path.exists() => match read_toggle_file(path) {
+                Ok(file) => file.latched,
+                Err(e) => {
+                    error!("Couldn't read toggle state ({}): {}", path.display(), e);
+                    BTreeSet::new()
+                }
+            },
+            _ => BTreeSet::new(),
+        };
+
+        Self { path, latched }
+    }
+
+    pub fn is_latched(&self, key: u32) -> bool {
+        self.latched.contains(&key)
+    }
+
+    /// Flips the state of `key` and returns whether it is now latched.
+    pub fn flip(&mut self, key: u32) -> bool {
+        let latched = if self.latched.remove(&key) {
+            false
+        } else {
+            self.latched.insert(key)
+        };
+
+        self.save();
+
+        latched
+    }
+
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => {
+                debug!("No location for toggle state, not persisting");
+                return;
+            }
+        };
+
+        let file = ToggleFile {
+            latched: self.latched.clone(),
+        };
+
+        let result = toml::to_string(&file)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| Ok(write_atomic(path, &contents)?));
+
+        if let Err(e) = result {
+            error!("Couldn't save toggle state ({}): {}", path.display(), e);
+        }
+    }
+}
+
+fn read_toggle_file(path: &Path) -> Result<ToggleFile> {
+    Ok(toml::from_str(&fs::read_to_string(path)?)?)
+}